@@ -16,7 +16,7 @@ pub fn test_position(search_state: &mut SearchState, fen: &str, depth: usize) ->
     search(&board_position, search_state);
 
     let time = search_state.stop_condition.started_search.elapsed().as_micros();
-    BenchResults{nodes: search_state.nodes, time: time}
+    BenchResults{nodes: search_state.nodes, time}
 }
 
 
@@ -20,8 +20,8 @@
 
 use std::io::{self, Write};
 
-use crate::evaluation::nnue::NNUE;
-use crate::movegen::move_gen::generate_all_moves;
+use crate::evaluation::nnue::embedded_network;
+use crate::movegen::move_gen::generate_legal_moves;
 use crate::primitives::board::BoardPosition;
 use crate::primitives::consts::MIN_DEPTH;
 use crate::primitives::shared::{Move, Piece, START_POSITION};
@@ -123,16 +123,7 @@ fn play_random_plies(
     let mut board = BoardPosition::new(start_fen);
 
     for _ in 0..plies {
-        let pseudo = generate_all_moves(&board);
-
-        // Filter down to legal moves (pseudo-legal movegen can leave the king
-        // en prise when pinned).
-        let mut legal: Vec<Move> = Vec::with_capacity(pseudo.len());
-        for mv in &pseudo {
-            if board.make_move(mv.mv).is_some() {
-                legal.push(mv.mv);
-            }
-        }
+        let legal: Vec<Move> = generate_legal_moves(&board).iter().map(|entry| entry.mv).collect();
 
         if legal.is_empty() {
             return None;
@@ -145,7 +136,7 @@ fn play_random_plies(
         board = new_board;
     }
 
-    search_state.network_state.start_board(&board, &NNUE);
+    search_state.network_state.start_board(&board, &search_state.engine_config.network);
 
     // Match normal play: the search starts at ply 0 while the played plies
     // remain in the repetition table for draw detection.
@@ -165,6 +156,9 @@ fn generate(n: usize, seed: u64, book: &[String], out: &mut dyn Write) -> io::Re
     let mut search_state = SearchState::new(&EngineConfig {
         hash: 0,
         soft_nodes: None,
+        threads: 1,
+        multi_pv: 1,
+        network: embedded_network(),
     });
     search_state.reporting = Reporting::Quiet;
 
@@ -218,7 +212,7 @@ pub fn run_genfens(tokens: Vec<&str>) {
         match tokens[i] {
             "seed" => {
                 if i + 1 < tokens.len() {
-                    seed = parse_seed(&tokens[i + 1]);
+                    seed = parse_seed(tokens[i + 1]);
                 }
                 i += 2;
             }
@@ -1,11 +1,46 @@
 use crate::primitives::board::BoardPosition;
+use crate::primitives::consts::MATE_THRESHOLD;
+use crate::primitives::shared::{ASCII_PIECES, Piece};
 use crate::search_objs::search_state::SearchState;
 
-pub fn nnue_evaluate(board_position: &BoardPosition, search_state: &SearchState) -> i32 {
-    search_state.network_state.evaluate(board_position.side)
+pub fn nnue_evaluate(board_position: &BoardPosition, search_state: &mut SearchState) -> i32 {
+    // Never let the net claim a mate
+    let piece_count = board_position.occupancies[2].count_ones();
+    search_state.network_state.evaluate(board_position.side, piece_count, &search_state.engine_config.network).clamp(-MATE_THRESHOLD + 1, MATE_THRESHOLD - 1)
 }
 
-pub fn evaltest(board_position: &BoardPosition, search_state: &SearchState) {
+pub fn evaltest(board_position: &BoardPosition, search_state: &mut SearchState) {
+    let breakdown = search_state.network_state.breakdown(board_position, &search_state.engine_config.network);
+    let separator = "+-------".repeat(8) + "+";
+
+    println!("NNUE derived piece values (centipawns, white's point of view):");
+    println!("{}", separator);
+    for rank in 0..8 {
+        let mut pieces = String::new();
+        let mut values = String::new();
+
+        for file in 0..8 {
+            let square = rank * 8 + file;
+            let piece = board_position.mailbox[square];
+
+            if piece == Piece::NONE {
+                pieces += "|       ";
+            } else {
+                pieces += &format!("|   {}   ", char::from(ASCII_PIECES[piece as usize]));
+            }
+
+            match breakdown.contributions[square] {
+                Some(value) => values += &format!("| {:>5} ", value),
+                None => values += "|       ",
+            }
+        }
+
+        println!("{}|", pieces);
+        println!("{}|", values);
+        println!("{}", separator);
+    }
+
+    println!("Raw output: {:.4} (output bucket {})", breakdown.raw_output, breakdown.output_bucket);
     println!("NNUE: {}", nnue_evaluate(board_position, search_state));
 }
 
@@ -31,13 +66,13 @@ use crate::search_objs::search_state::SearchState;
         let mv = Move::create(62 , 53 , MoveCode::QuietMove); // Nf3
         let board_after_move = board_position.make_move(mv).unwrap();
 
-        let eval1 = nnue_evaluate(&board_position, &search_state);
+        let eval1 = nnue_evaluate(&board_position, &mut search_state);
         search_state.make_move(mv, &board_position, eval1);
-        let eval2 = nnue_evaluate(&board_after_move, &search_state);
+        let eval2 = nnue_evaluate(&board_after_move, &mut search_state);
         search_state.take_back();
-        let eval3 = nnue_evaluate(&board_position, &search_state);
+        let eval3 = nnue_evaluate(&board_position, &mut search_state);
         search_state.make_move(mv, &board_position, eval3);
-        let eval4 = nnue_evaluate(&board_after_move, &search_state);
+        let eval4 = nnue_evaluate(&board_after_move, &mut search_state);
         println!("{} - {} - {} - {}", eval1, eval2, eval3, eval4);
 
         assert_eq!(eval1, eval3);
@@ -1,3 +1,4 @@
 pub mod evaluate;
 pub mod network_state;
-pub mod nnue;
\ No newline at end of file
+pub mod nnue;
+pub mod simd;
\ No newline at end of file
@@ -1,103 +1,181 @@
-use crate::evaluation::nnue::{Accumulator, NNUE, Network, feature_index};
+use arrayvec::ArrayVec;
+
+use crate::evaluation::nnue::{Accumulator, InputBucket, Network, Weights, feature_index};
 use crate::primitives::board::{BoardPosition};
-use crate::primitives::shared::{Color::{self, White}, Move, Piece};
+use crate::primitives::shared::{Color::{self, Black, White}, Move, Piece};
 
-pub struct NetworkState {
-    pub accumulators: Vec<[Accumulator; 2]>,
-    //pub feature_list: Vec<Feature>
+/// Finny table entry - the last accumulator built for a king bucket and the pieces it was built from
+#[derive(Clone)]
+struct CachedAccumulator<const H: usize> {
+    accumulator: Accumulator<H>,
+    bitboards: [u64; 12],
 }
 
-impl Default for NetworkState {
-    fn default() -> Self {
-        NetworkState { 
-            accumulators: vec![[Accumulator::new(&NNUE); 2]],
-            //feature_list: vec![]
-        }
-    }
+type FeatureList = ArrayVec<(Piece, u8), 2>;
+
+/// Feature changes of a move, as (piece, square) pairs in `add, sub, add, sub` order
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Copy, Debug)]
+pub enum Feature {
+    AddSub(Piece, u8, Piece, u8),
+    AddSubSub(Piece, u8, Piece, u8, Piece, u8),
+    AddSubAddSub(Piece, u8, Piece, u8, Piece, u8, Piece, u8),
 }
 
-impl NetworkState {
+impl Feature {
+    /// Writes `prev` with the changes applied into `accumulator`, in one pass over the weights
+    fn apply<const H: usize>(self, accumulator: &mut Accumulator<H>, prev: &Accumulator<H>, perspective: Color, bucket: InputBucket, net: &Weights<H>) {
+        let idx = |piece, square| feature_index(perspective, bucket, piece, square);
 
-    pub fn remove_feature(&mut self, piece: Piece, square: u8, accumulators: &mut [Accumulator;2]) {
-        let flipped_sq = square ^ 56;
+        match self {
+            Feature::AddSub(add_piece, add_sq, sub_piece, sub_sq) => {
+                accumulator.add_sub(prev, idx(add_piece, add_sq), idx(sub_piece, sub_sq), net)
+            }
+            Feature::AddSubSub(add_piece, add_sq, sub_piece1, sub_sq1, sub_piece2, sub_sq2) => {
+                accumulator.add_sub_sub(prev, idx(add_piece, add_sq), idx(sub_piece1, sub_sq1), idx(sub_piece2, sub_sq2), net)
+            }
+            Feature::AddSubAddSub(add_piece1, add_sq1, sub_piece1, sub_sq1, add_piece2, add_sq2, sub_piece2, sub_sq2) => {
+                accumulator.add_sub_add_sub(
+                    prev,
+                    idx(add_piece1, add_sq1),
+                    idx(sub_piece1, sub_sq1),
+                    idx(add_piece2, add_sq2),
+                    idx(sub_piece2, sub_sq2),
+                    net,
+                )
+            }
+        }
+    }
 
-        // mirrored perspective for black (flip the top 3 bits)
-        let feature = feature_index(piece, flipped_sq as usize);
-        accumulators[0].remove_feature(feature, &NNUE);
+    // (added, removed)
+    fn features(self) -> (FeatureList, FeatureList) {
+        let mut added = ArrayVec::new();
+        let mut removed = ArrayVec::new();
 
-        let black_feature =
-            feature_index(piece.flip_color(), square as usize);
-        accumulators[1].remove_feature(black_feature, &NNUE);
+        match self {
+            Feature::AddSub(add_piece, add_sq, sub_piece, sub_sq) => {
+                added.push((add_piece, add_sq));
+                removed.push((sub_piece, sub_sq));
+            }
+            Feature::AddSubSub(add_piece, add_sq, sub_piece1, sub_sq1, sub_piece2, sub_sq2) => {
+                added.push((add_piece, add_sq));
+                removed.push((sub_piece1, sub_sq1));
+                removed.push((sub_piece2, sub_sq2));
+            }
+            Feature::AddSubAddSub(add_piece1, add_sq1, sub_piece1, sub_sq1, add_piece2, add_sq2, sub_piece2, sub_sq2) => {
+                added.push((add_piece1, add_sq1));
+                added.push((add_piece2, add_sq2));
+                removed.push((sub_piece1, sub_sq1));
+                removed.push((sub_piece2, sub_sq2));
+            }
+        }
+
+        (added, removed)
     }
+}
 
+/// One ply of the search line. The accumulators of a perspective are only valid once `computed`,
+/// until then they're the previous ply's with `update` applied.
+#[derive(Clone, Copy)]
+struct AccumulatorEntry<const H: usize> {
+    accumulators: [Accumulator<H>; 2],
+    computed: [bool; 2],
+    buckets: [InputBucket; 2],
+    update: Option<Feature>,
+}
 
-    pub fn add_feature(&mut self, piece: Piece, square: u8, accumulators: &mut [Accumulator;2]) {
-        let flipped_sq = square ^ 56;
+/// Accumulators of the current search line for a net with `H` hidden neurons.
+/// Moves only record their feature changes, the accumulators are built when a position is evaluated.
+pub struct AccumulatorStack<const H: usize> {
+    // Entries past `head` are left over from earlier lines and get reused, so pushing never copies
+    stack: Vec<AccumulatorEntry<H>>,
+    head: usize,
+    // Per perspective, indexed by `InputBucket::index`
+    refresh_cache: [Vec<CachedAccumulator<H>>; 2],
+}
 
-        // mirrored perspective for black (flip the top 3 bits)
-        let feature = feature_index(piece, flipped_sq as usize);
-        accumulators[0].add_feature(feature, &NNUE);
+impl<const H: usize> AccumulatorStack<H> {
+    pub fn new(net: &Weights<H>) -> Self {
+        let empty = CachedAccumulator { accumulator: Accumulator::new(net), bitboards: [0; 12] };
+        let root = AccumulatorEntry {
+            accumulators: [Accumulator::new(net); 2],
+            computed: [true; 2],
+            buckets: [net.layout().bucket(White, 60), net.layout().bucket(Black, 4)],
+            update: None,
+        };
 
-        let black_feature =
-            feature_index(piece.flip_color(), square as usize);
-        accumulators[1].add_feature(black_feature, &NNUE);
+        AccumulatorStack { 
+            stack: vec![root],
+            head: 0,
+            refresh_cache: [(); 2].map(|_| vec![empty.clone(); 2 * net.layout().bucket_count()]),
+        }
     }
 
-    pub fn start_board(&mut self, board_position: &BoardPosition, net: &Network) {
-        let mut accumulators = [Accumulator::new(net); 2];
+    fn king_square(board_position: &BoardPosition, perspective: Color) -> u8 {
+        let king = if perspective == White { Piece::K } else { Piece::k };
+        board_position.bitboards[king as usize].trailing_zeros() as u8
+    }
 
-        for square in 0..64 {
-            //let nnue_square = square ^ 7;
-            
-            let flipped_sq = square ^ 56;
-            let piece = board_position.mailbox[square];
+    // Accumulator<H> of one perspective for the whole board. Starts from the bucket's cached
+    // accumulator and only applies the pieces that differ from when it was cached.
+    fn refresh(&mut self, board_position: &BoardPosition, perspective: Color, bucket: InputBucket, net: &Weights<H>) -> Accumulator<H> {
+        let cached = &mut self.refresh_cache[perspective][bucket.index()];
 
-            if piece == Piece::NONE {
-                continue;
+        for piece_idx in 0..12 {
+            let piece = Piece::new(piece_idx);
+            let mut added = board_position.bitboards[piece_idx] & !cached.bitboards[piece_idx];
+            let mut removed = cached.bitboards[piece_idx] & !board_position.bitboards[piece_idx];
+
+            while added != 0 {
+                let square = added.trailing_zeros() as u8;
+                cached.accumulator.add_feature(feature_index(perspective, bucket, piece, square), net);
+                added &= added - 1;
             }
 
-            // mirrored perspective for white (flip the top 3 bits)
-            let feature = feature_index(piece, flipped_sq);
+            while removed != 0 {
+                let square = removed.trailing_zeros() as u8;
+                cached.accumulator.remove_feature(feature_index(perspective, bucket, piece, square), net);
+                removed &= removed - 1;
+            }
 
-            accumulators[0].add_feature(feature, net);
+            cached.bitboards[piece_idx] = board_position.bitboards[piece_idx];
+        }
 
-            let black_feature =
-                feature_index(piece.flip_color(), square);
+        cached.accumulator
+    }
 
-            accumulators[1].add_feature(black_feature, net);
-        }
+    pub fn start_board(&mut self, board_position: &BoardPosition, net: &Weights<H>) {
+        let buckets = [White, Black].map(|perspective| {
+            net.layout().bucket(perspective, Self::king_square(board_position, perspective))
+        });
+        let accumulators = [White, Black].map(|perspective| {
+            self.refresh(board_position, perspective, buckets[perspective], net)
+        });
 
-        self.accumulators.clear();
-        self.accumulators.push(accumulators);
+        self.head = 0;
+        self.stack[0] = AccumulatorEntry { accumulators, computed: [true; 2], buckets, update: None };
     }
 
     // Board state pre move
-    pub fn apply_move(&mut self, mv: Move, board_position: &BoardPosition) {
+    pub fn apply_move(&mut self, mv: Move, board_position: &BoardPosition, net: &Weights<H>) {
         let piece = board_position.get_piece(mv);
         let new_piece = if mv.get_promoted_piece(board_position.side) == Piece::NONE { piece } else {mv.get_promoted_piece(board_position.side)};
-        let mut accumulators = self.accumulators.last().unwrap().clone();
+        let source = mv.get_source_square();
+        let target = mv.get_target_square();
 
-        self.remove_feature(piece, mv.get_source_square(), &mut accumulators);
-        self.add_feature(new_piece, mv.get_target_square(), &mut accumulators);
-
-        if mv.is_capture() {
-            if mv.is_enpassant() {
-                let ep_sq = if board_position.side == White {
-                    mv.get_target_square() + 8
-                } else {
-                    mv.get_target_square() - 8
-                };
-
-                self.remove_feature(board_position.get_victim(mv), ep_sq, &mut accumulators);
-                //self.feature_list.push(AddSubSub(new_piece, target, piece, source, board_position.get_victim(mv), ep_sq));
+        let update = if mv.is_capture() {
+            let victim_sq = if !mv.is_enpassant() {
+                target
+            } else if board_position.side == White {
+                target + 8
             } else {
-                self.remove_feature(board_position.get_victim(mv), mv.get_target_square(), &mut accumulators);
-                //self.feature_list.push(AddSubSub(new_piece, target, piece, source, board_position.get_victim(mv), target));
-            }
+                target - 8
+            };
 
+            Feature::AddSubSub(new_piece, target, piece, source, board_position.get_victim(mv), victim_sq)
         } else if mv.get_castling() {
         // White kingside (O-O): king e1->g1, rook h1->f1
-            let (rook_piece, rook_from, rook_to) = match mv.get_target_square() {
+            let (rook_piece, rook_from, rook_to) = match target {
                 62 => (Piece::R, 63, 61),
                 58 => (Piece::R, 56, 59),
                 6  => (Piece::r, 7, 5),
@@ -105,38 +183,332 @@ impl NetworkState {
                 _ => unsafe { std::hint::unreachable_unchecked() }
             };
 
-            self.add_feature(rook_piece, rook_to, &mut accumulators);
-            self.remove_feature(rook_piece, rook_from, &mut accumulators);
-            //self.feature_list.push(AddSubAddSub(new_piece, target, piece, source, rook_piece, rook_to, rook_piece, rook_from));
-
+            Feature::AddSubAddSub(new_piece, target, piece, source, rook_piece, rook_to, rook_piece, rook_from)
         } else {
-            //self.feature_list.push(AddSub(new_piece, target, piece, source));
+            Feature::AddSub(new_piece, target, piece, source)
+        };
+
+        let previous_buckets = self.stack[self.head].buckets;
+        let mut buckets = previous_buckets;
+        let mut computed = [false; 2];
+        let mut refreshed = None;
+
+        // Only the mover's king can change its perspective's bucket or mirroring.
+        // A different bucket shares no inputs with the old one, so that perspective is rebuilt
+        // from the pre-move board right away - the board isn't around any more once evaluated.
+        if piece == Piece::K || piece == Piece::k {
+            let perspective = board_position.side;
+            let bucket = net.layout().bucket(perspective, target);
+
+            if bucket != previous_buckets[perspective] {
+                let mut accumulator = self.refresh(board_position, perspective, bucket, net);
+                let (added, removed) = update.features();
+
+                for &(piece, square) in &removed {
+                    accumulator.remove_feature(feature_index(perspective, bucket, piece, square), net);
+                }
+                for &(piece, square) in &added {
+                    accumulator.add_feature(feature_index(perspective, bucket, piece, square), net);
+                }
+
+                buckets[perspective] = bucket;
+                computed[perspective] = true;
+                refreshed = Some((perspective, accumulator));
+            }
         }
 
-        self.accumulators.push(accumulators);
+        self.head += 1;
+        if self.head == self.stack.len() {
+            self.stack.push(self.stack[self.head - 1]);
+        }
+
+        let entry = &mut self.stack[self.head];
+        entry.computed = computed;
+        entry.buckets = buckets;
+        entry.update = Some(update);
+        if let Some((perspective, accumulator)) = refreshed {
+            entry.accumulators[perspective] = accumulator;
+        }
     }
 
     pub fn undo_move(&mut self) {
-        self.accumulators.pop();
+        self.head -= 1;
     }
 
-    pub fn evaluate(&self, stm: Color) -> i32 {
-        let accumulators= self.accumulators.last().unwrap();
+    // Walks back to the last ply this perspective was computed at and replays the updates from there
+    fn materialize(&mut self, perspective: Color, net: &Weights<H>) {
+        let mut start = self.head;
+        while !self.stack[start].computed[perspective] {
+            start -= 1;
+        }
+
+        for ply in start + 1..=self.head {
+            let (before, after) = self.stack.split_at_mut(ply);
+            let prev = &before[ply - 1].accumulators[perspective];
+            let entry = &mut after[0];
+
+            entry.update.unwrap().apply(&mut entry.accumulators[perspective], prev, perspective, entry.buckets[perspective], net);
+            entry.computed[perspective] = true;
+        }
+    }
+
+    /// Accumulators of the current position, built from pending updates if needed
+    pub fn accumulators(&mut self, net: &Weights<H>) -> &[Accumulator<H>; 2] {
+        self.materialize(White, net);
+        self.materialize(Black, net);
+
+        &self.stack[self.head].accumulators
+    }
+
+    // The piece count picks the net's output bucket
+    pub fn evaluate(&mut self, stm: Color, piece_count: u32, net: &Weights<H>) -> i32 {
+        let accumulators = self.accumulators(net);
         
-        NNUE.evaluate(
+        net.evaluate(
             &accumulators[stm],
             &accumulators[stm.invert()],
+            piece_count,
         )
     }
+
+    /// Eval of the current position with every piece but the kings taken off in turn.
+    /// Only copies of the accumulators are touched.
+    pub fn breakdown(&mut self, board_position: &BoardPosition, net: &Weights<H>) -> EvalBreakdown {
+        let stm = board_position.side;
+        let piece_count = board_position.occupancies[2].count_ones();
+        let buckets = self.stack[self.head].buckets;
+        let accumulators = *self.accumulators(net);
+
+        let eval = net.evaluate(&accumulators[stm], &accumulators[stm.invert()], piece_count);
+        let mut contributions = [None; 64];
+
+        for (square, &piece) in board_position.mailbox.iter().enumerate() {
+            // Without its king a perspective has no bucket
+            if piece == Piece::NONE || piece == Piece::K || piece == Piece::k {
+                continue;
+            }
+
+            let mut without = accumulators;
+            for perspective in [White, Black] {
+                without[perspective].remove_feature(feature_index(perspective, buckets[perspective], piece, square as u8), net);
+            }
+
+            let eval_without = net.evaluate(&without[stm], &without[stm.invert()], piece_count - 1);
+            let contribution = eval - eval_without;
+            contributions[square] = Some(if stm == White { contribution } else { -contribution });
+        }
+
+        EvalBreakdown {
+            raw_output: net.raw_output(&accumulators[stm], &accumulators[stm.invert()], piece_count),
+            output_bucket: net.architecture().output_bucket(piece_count),
+            contributions,
+        }
+    }
+}
+
+/// What `evaltest` prints
+pub struct EvalBreakdown {
+    /// Before the eval scale, from the side to move
+    pub raw_output: f32,
+    pub output_bucket: usize,
+    /// What the piece on the square is worth to white - how much the eval drops without it.
+    /// `None` for kings and empty squares.
+    pub contributions: [Option<i32>; 64],
 }
 
-// pub enum Feature {
-//     AddSub(Piece, u8, Piece, u8),
-//     AddSubSub(Piece, u8, Piece, u8, Piece, u8),
-//     AddSubAddSub(Piece, u8, Piece, u8, Piece, u8, Piece, u8)
-// }
+/// Accumulators of the current search line, sized for the net it was created with
+pub enum NetworkState {
+    H128(AccumulatorStack<128>),
+    H256(AccumulatorStack<256>),
+    H512(AccumulatorStack<512>),
+    H768(AccumulatorStack<768>),
+    H1024(AccumulatorStack<1024>),
+}
+
+// Runs `$body` with the stack and weights of the same hidden size
+macro_rules! with_stack {
+    ($state:expr, $net:expr, |$stack:ident, $weights:ident| $body:expr) => {
+        match ($state, $net) {
+            (NetworkState::H128($stack), Network::H128($weights)) => $body,
+            (NetworkState::H256($stack), Network::H256($weights)) => $body,
+            (NetworkState::H512($stack), Network::H512($weights)) => $body,
+            (NetworkState::H768($stack), Network::H768($weights)) => $body,
+            (NetworkState::H1024($stack), Network::H1024($weights)) => $body,
+            _ => unreachable!("network state was created for a different net"),
+        }
+    };
+}
 
-// impl Feature {
+impl NetworkState {
+    pub fn new(net: &Network) -> Self {
+        match net {
+            Network::H128(weights) => NetworkState::H128(AccumulatorStack::new(weights)),
+            Network::H256(weights) => NetworkState::H256(AccumulatorStack::new(weights)),
+            Network::H512(weights) => NetworkState::H512(AccumulatorStack::new(weights)),
+            Network::H768(weights) => NetworkState::H768(AccumulatorStack::new(weights)),
+            Network::H1024(weights) => NetworkState::H1024(AccumulatorStack::new(weights)),
+        }
+    }
+
+    pub fn start_board(&mut self, board_position: &BoardPosition, net: &Network) {
+        with_stack!(self, net, |stack, weights| stack.start_board(board_position, weights))
+    }
+
+    // Board state pre move
+    pub fn apply_move(&mut self, mv: Move, board_position: &BoardPosition, net: &Network) {
+        with_stack!(self, net, |stack, weights| stack.apply_move(mv, board_position, weights))
+    }
+
+    pub fn undo_move(&mut self) {
+        match self {
+            NetworkState::H128(stack) => stack.undo_move(),
+            NetworkState::H256(stack) => stack.undo_move(),
+            NetworkState::H512(stack) => stack.undo_move(),
+            NetworkState::H768(stack) => stack.undo_move(),
+            NetworkState::H1024(stack) => stack.undo_move(),
+        }
+    }
 
-    
-// }
\ No newline at end of file
+    // The piece count picks the net's output bucket
+    pub fn evaluate(&mut self, stm: Color, piece_count: u32, net: &Network) -> i32 {
+        with_stack!(self, net, |stack, weights| stack.evaluate(stm, piece_count, weights))
+    }
+
+    pub fn breakdown(&mut self, board_position: &BoardPosition, net: &Network) -> EvalBreakdown {
+        with_stack!(self, net, |stack, weights| stack.breakdown(board_position, weights))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use crate::evaluation::network_state::AccumulatorStack;
+    use crate::evaluation::nnue::{InputLayout, Network, embedded_network};
+    use crate::gui::parse_move;
+    use crate::primitives::board::BoardPosition;
+    use crate::primitives::shared::{KIWIPETE, START_POSITION};
+
+    #[test]
+    fn test_king_bucket_refresh() {
+        let builder = thread::Builder::new().stack_size(80 * 1024 * 1024);
+        let handler = builder
+            .spawn(|| {
+                // Small made up weights, only the bookkeeping is under test
+                let layout = InputLayout::HM_4;
+                let bytes: Vec<u8> = (0..Network::file_size(&layout) / 2)
+                    .flat_map(|i| (((i * 7919) % 17) as i16 - 8).to_le_bytes())
+                    .collect();
+                let net = Network::from_bytes(&bytes, layout).unwrap();
+                let Network::H256(net) = &*net else { unreachable!() };
+
+                // Castling over the mirror line, king walks through the rank buckets, a capture at the end
+                let moves = ["e1c1", "e8g8", "c1b2", "g8g7", "b2c3", "g7g8", "c3d4", "g8g7", "d4e5", "a8a1", "d1a1", "f8f5", "e5f5"];
+                let mut board_position = BoardPosition::new("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1");
+                let mut network_state = AccumulatorStack::new(net);
+                network_state.start_board(&board_position, net);
+
+                for mv in moves {
+                    let mv = parse_move(&board_position, mv).unwrap();
+                    network_state.apply_move(mv, &board_position, net);
+                    board_position = board_position.make_move(mv).unwrap();
+
+                    let mut fresh = AccumulatorStack::new(net);
+                    fresh.start_board(&board_position, net);
+                    assert_eq!(network_state.accumulators(net), fresh.accumulators(net));
+                }
+            })
+            .unwrap();
+        handler.join().unwrap();
+    }
+
+    #[test]
+    fn test_lazy_updates() {
+        let builder = thread::Builder::new().stack_size(80 * 1024 * 1024);
+        let handler = builder
+            .spawn(|| {
+                let net = embedded_network();
+                let Network::H256(net) = &*net else { unreachable!() };
+                let mut board_position = BoardPosition::new(KIWIPETE);
+                let mut network_state = AccumulatorStack::new(net);
+                network_state.start_board(&board_position, net);
+
+                // Nothing is evaluated along the way, every update is applied at the end
+                let mut boards = vec![board_position.clone()];
+                for mv in ["e1c1", "e8g8", "e5g6", "h3g2", "g6f8", "g2h1q"] {
+                    let mv = parse_move(&board_position, mv).unwrap();
+                    network_state.apply_move(mv, &board_position, net);
+                    board_position = board_position.make_move(mv).unwrap();
+                    boards.push(board_position.clone());
+                }
+
+                let mut fresh = AccumulatorStack::new(net);
+                fresh.start_board(&board_position, net);
+                assert_eq!(network_state.accumulators(net), fresh.accumulators(net));
+
+                // Going back to plies that were skipped over
+                for _ in 0..3 {
+                    network_state.undo_move();
+                    boards.pop();
+                }
+                let mv = parse_move(boards.last().unwrap(), "a6e2").unwrap();
+                network_state.apply_move(mv, boards.last().unwrap(), net);
+                let board_position = boards.last().unwrap().make_move(mv).unwrap();
+
+                fresh.start_board(&board_position, net);
+                assert_eq!(network_state.accumulators(net), fresh.accumulators(net));
+            })
+            .unwrap();
+        handler.join().unwrap();
+    }
+
+    #[test]
+    fn test_eval_breakdown() {
+        let builder = thread::Builder::new().stack_size(80 * 1024 * 1024);
+        let handler = builder
+            .spawn(|| {
+                let net = embedded_network();
+                let Network::H256(net) = &*net else { unreachable!() };
+                let mut network_state = AccumulatorStack::new(net);
+
+                // Black to move, so values get flipped to white's point of view
+                let board_position = BoardPosition::new("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1");
+                network_state.start_board(&board_position, net);
+                let breakdown = network_state.breakdown(&board_position, net);
+                let eval = network_state.evaluate(board_position.side, 32, net);
+
+                assert_eq!(breakdown.contributions.iter().flatten().count(), 30);
+                assert!(breakdown.contributions[4].is_none() && breakdown.contributions[60].is_none());
+
+                // Same as evaluating the position without the white queen
+                let mut fresh = AccumulatorStack::new(net);
+                fresh.start_board(&BoardPosition::new("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNB1KBNR b KQkq - 0 1"), net);
+                let eval_without = fresh.evaluate(board_position.side, 31, net);
+                assert_eq!(breakdown.contributions[59], Some(eval_without - eval));
+            })
+            .unwrap();
+        handler.join().unwrap();
+    }
+
+    #[test]
+    fn test_refresh_cache() {
+        let builder = thread::Builder::new().stack_size(80 * 1024 * 1024);
+        let handler = builder
+            .spawn(|| {
+                let net = embedded_network();
+                let Network::H256(net) = &*net else { unreachable!() };
+                let mut network_state = AccumulatorStack::new(net);
+
+                // Every position after the first is refreshed from the cache
+                for fen in [START_POSITION, KIWIPETE, "8/7p/P1N2k2/1BBp2p1/4b1K1/6P1/r7/8 b - - 1 49", START_POSITION] {
+                    let board_position = BoardPosition::new(fen);
+                    network_state.start_board(&board_position, net);
+
+                    let mut fresh = AccumulatorStack::new(net);
+                    fresh.start_board(&board_position, net);
+                    assert_eq!(network_state.accumulators(net), fresh.accumulators(net));
+                }
+            })
+            .unwrap();
+        handler.join().unwrap();
+    }
+}
@@ -1,304 +1,750 @@
-use crate::primitives::shared::Piece;
-use std::arch::x86_64::*;
+use crate::evaluation::simd;
+use crate::primitives::shared::{Color::{self, White}, Piece};
+use std::sync::{Arc, OnceLock};
 
+/// Hidden size and quantisation of bare bullet nets like the embedded one.
+/// Nets with a header bring their own.
 pub const HIDDEN_SIZE: usize = 256;
 const SCALE: i32 = 400;
 const QA: i16 = 255;
 const QB: i16 = 64;
+/// The SIMD SCReLU kernels multiply activations clamped to QA by the output weights in i16 lanes
+const MAX_QA: i16 = 255;
 
-pub static NNUE: Network =
-    unsafe { std::mem::transmute(*include_bytes!("../../nets/quantised-256.bin")) };
+/// Net compiled into the binary, used until `EvalFile` points somewhere else
+static EMBEDDED_NET: &[u8] = include_bytes!("../../nets/quantised-256.bin");
+static EMBEDDED_NETWORK: OnceLock<Arc<Network>> = OnceLock::new();
 
-#[inline]
-/// Square Clipped ReLU - Activation Function.
-/// Note that this takes the i16s in the accumulator to i32s.
-/// Range is 0.0 .. 1.0 (in other words, 0 to QA*QA quantized).
-fn screlu(x: i16) -> i32 {
-    let y = i32::from(x).clamp(0, i32::from(QA));
-    y * y
+pub fn embedded_network() -> Arc<Network> {
+    EMBEDDED_NETWORK
+        .get_or_init(|| Network::from_bytes(EMBEDDED_NET, InputLayout::FLAT).expect("embedded net doesn't match Network"))
+        .clone()
 }
 
-/// This is the quantised format that bullet outputs.
-#[repr(C)]
-pub struct Network {
-    /// Column-Major `HIDDEN_SIZE x 768` matrix.
-    /// Values have quantization of QA.
-    feature_weights: [Accumulator; 768],
-    /// Vector with dimension `HIDDEN_SIZE`.
-    /// Values have quantization of QA.
-    feature_bias: Accumulator,
-    /// Column-Major `1 x (2 * HIDDEN_SIZE)`
-    /// matrix, we use it like this to make the
-    /// code nicer in `Network::evaluate`.
-    /// Values have quantization of QB.
-    output_weights: [i16; 2 * HIDDEN_SIZE],
-    /// Scalar output bias.
-    /// Value has quantization of QA * QB.
-    output_bias: i16,
+/// King bucket layout of the input layer. Every bucket has its own 768 inputs,
+/// picked by where the perspective's own king stands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InputLayout {
+    /// Bucket of each king square, a1 = 0, from the perspective's own side of the board
+    pub buckets: [u8; 64],
+    /// Horizontal mirroring - with the king on files e-h the board is flipped so it's always on a-d
+    pub mirrored: bool,
 }
 
-impl Network {
-    /// Calculates the output of the network, starting from the already
-    /// calculated hidden layer (done efficiently during makemoves).
-    pub fn evaluate(&self, us: &Accumulator, them: &Accumulator) -> i32 {
-        // Initialise output.
-        let mut output = 0;
-
-        if is_x86_feature_detected!("avx2") {
-            unsafe {
-                output =
-                    screlu_dot_avx2(
-                        &us.vals,
-                        &self.output_weights[..HIDDEN_SIZE]
-                            .try_into()
-                            .unwrap(),
-                    );
-
-                output +=
-                    screlu_dot_avx2(
-                        &them.vals,
-                        &self.output_weights[HIDDEN_SIZE..]
-                            .try_into()
-                            .unwrap(),
-                    );
+impl InputLayout {
+    /// Plain 768 piece-square inputs
+    pub const FLAT: InputLayout = InputLayout { buckets: [0; 64], mirrored: false };
+
+    /// Mirrored, king on the first rank, second rank, third or fourth rank, or further up
+    #[rustfmt::skip]
+    pub const HM_4: InputLayout = InputLayout {
+        buckets: [
+            0, 0, 0, 0, 0, 0, 0, 0,
+            1, 1, 1, 1, 1, 1, 1, 1,
+            2, 2, 2, 2, 2, 2, 2, 2,
+            2, 2, 2, 2, 2, 2, 2, 2,
+            3, 3, 3, 3, 3, 3, 3, 3,
+            3, 3, 3, 3, 3, 3, 3, 3,
+            3, 3, 3, 3, 3, 3, 3, 3,
+            3, 3, 3, 3, 3, 3, 3, 3,
+        ],
+        mirrored: true,
+    };
+
+    /// Layouts `EvalFile` knows about. Nets don't say what they were trained with,
+    /// so each layout needs a distinct bucket count to be told apart by file size.
+    pub const KNOWN: [InputLayout; 2] = [InputLayout::FLAT, InputLayout::HM_4];
+
+    pub const fn bucket_count(&self) -> usize {
+        let mut max = 0;
+        let mut square = 0;
+        while square < 64 {
+            if self.buckets[square] > max {
+                max = self.buckets[square];
             }
-        } else {
+            square += 1;
+        }
+        max as usize + 1
+    }
+
+    /// Input bucket for a perspective whose king is on `king_square`
+    #[inline(always)]
+    pub fn bucket(&self, perspective: Color, king_square: u8) -> InputBucket {
+        let king_square = orient(perspective, king_square);
+        let flip = if self.mirrored && king_square % 8 >= 4 { 7 } else { 0 };
+
+        InputBucket {
+            offset: self.buckets[(king_square ^ flip) as usize] as usize * 768,
+            flip,
+        }
+    }
+}
+
+/// Where a perspective's features live - a bucket of the input layer and the horizontal flip
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InputBucket {
+    offset: usize,
+    flip: u8,
+}
+
+impl InputBucket {
+    /// Distinct for every bucket and mirroring, below `2 * bucket_count`
+    #[inline(always)]
+    pub fn index(&self) -> usize {
+        self.offset / 768 * 2 + (self.flip != 0) as usize
+    }
+}
+
+
+/// Identifies a net file with a header, anything else is read as a bare bullet net
+pub const NET_MAGIC: &[u8; 8] = b"DUALNNUE";
+pub const NET_VERSION: u16 = 2;
+/// Header bytes, the weights start right after
+pub const NET_HEADER_SIZE: usize = 64;
+/// Hidden sizes with an evaluator compiled in
+pub const HIDDEN_SIZES: [usize; 5] = [128, 256, 512, 768, 1024];
+/// Largest layer after L1
+pub const MAX_DENSE: usize = 64;
+pub const MAX_OUTPUT_BUCKETS: usize = 32;
+
+/// Layers after the feature transformer, described by the net file's header.
+/// Bare nets are `Architecture::PLAIN`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Architecture {
+    /// Float layers between L1 and the output, 0 when left out
+    pub l2: usize,
+    pub l3: usize,
+    /// L1 activations are the products of the two halves of each accumulator
+    pub pairwise: bool,
+    /// Output layers picked by the number of pieces on the board
+    pub output_buckets: usize,
+}
 
-            // Side-To-Move Accumulator -> Output.
-            for (&input, &weight) in us.vals.iter().zip(&self.output_weights[..HIDDEN_SIZE]) {
-                output += screlu(input) * i32::from(weight);
+impl Architecture {
+    /// (768 -> hidden) x 2 -> 1, SCReLU
+    pub const PLAIN: Architecture = Architecture { l2: 0, l3: 0, pairwise: false, output_buckets: 1 };
+
+    /// Activations of one perspective
+    pub const fn l1_outputs(&self, hidden_size: usize) -> usize {
+        if self.pairwise { hidden_size / 2 } else { hidden_size }
+    }
+
+    /// Bytes of everything after the header
+    pub const fn body_size(&self, layout: &InputLayout, hidden_size: usize) -> usize {
+        let mut bytes = (768 * layout.bucket_count() + 1) * hidden_size * 2;
+
+        if self.l2 == 0 {
+            bytes += self.output_buckets * (2 * self.l1_outputs(hidden_size) + 1) * 2;
+        } else {
+            // Weights then biases, f32
+            bytes += self.output_buckets * (2 * self.l1_outputs(hidden_size) + 1) * self.l2 * 4;
+            if self.l3 != 0 {
+                bytes += self.output_buckets * (self.l2 + 1) * self.l3 * 4;
             }
+            let last = if self.l3 == 0 { self.l2 } else { self.l3 };
+            bytes += self.output_buckets * (last + 1) * 4;
+        }
+
+        bytes
+    }
+
+    /// Same split as bullet's `MaterialCount` - equally sized ranges of the piece count
+    #[inline(always)]
+    pub fn output_bucket(&self, piece_count: u32) -> usize {
+        let divisor = 32usize.div_ceil(self.output_buckets);
+        ((piece_count as usize).saturating_sub(2) / divisor).min(self.output_buckets - 1)
+    }
+}
+
+/// Everything needed to read a net file's weights.
+///
+/// The header is `NET_HEADER_SIZE` bytes, little endian, zero padded:
+/// magic (8), version u16, hidden size u16, QA i16, QB i16, scale u16, feature set u8
+/// (index into `InputLayout::KNOWN`), pairwise u8, L2 u16, L3 u16, output buckets u8.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NetHeader {
+    pub hidden_size: usize,
+    pub qa: i16,
+    pub qb: i16,
+    pub scale: i32,
+    pub layout: InputLayout,
+    pub arch: Architecture,
+}
+
+impl NetHeader {
+    /// What bare nets with this layout are taken to be
+    pub const fn bare(layout: InputLayout) -> NetHeader {
+        NetHeader { hidden_size: HIDDEN_SIZE, qa: QA, qb: QB, scale: SCALE, layout, arch: Architecture::PLAIN }
+    }
+
+    /// Size of the whole file, bullet pads it to a multiple of 64 bytes
+    pub const fn file_size(&self) -> usize {
+        (NET_HEADER_SIZE + self.arch.body_size(&self.layout, self.hidden_size)).next_multiple_of(64)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<NetHeader, String> {
+        if bytes.len() < NET_HEADER_SIZE || !bytes.starts_with(NET_MAGIC) {
+            return Err("network header is missing or truncated".to_string());
+        }
+
+        let read_u16 = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]);
+        let read_i16 = |at: usize| i16::from_le_bytes([bytes[at], bytes[at + 1]]);
+
+        if read_u16(8) != NET_VERSION {
+            return Err(format!("network file version {} is not supported, expected {}", read_u16(8), NET_VERSION));
+        }
+
+        let hidden_size = read_u16(10) as usize;
+        if !HIDDEN_SIZES.contains(&hidden_size) {
+            return Err(format!("network has hidden size {}, supported sizes are {:?}", hidden_size, HIDDEN_SIZES));
+        }
+
+        let (qa, qb, scale) = (read_i16(12), read_i16(14), read_u16(16) as i32);
+        if !(1..=MAX_QA).contains(&qa) {
+            return Err(format!("network has QA {}, supported values are 1 to {}", qa, MAX_QA));
+        }
+        if qb <= 0 || scale == 0 {
+            return Err(format!("invalid quantisation QA {} QB {} scale {} in network header", qa, qb, scale));
+        }
+
+        let layout = match InputLayout::KNOWN.get(bytes[18] as usize) {
+            Some(layout) => *layout,
+            None => return Err(format!("unknown feature set {} in network header", bytes[18])),
+        };
+
+        let arch = Architecture {
+            pairwise: match bytes[19] {
+                0 => false,
+                1 => true,
+                flag => return Err(format!("invalid pairwise flag {} in network header", flag)),
+            },
+            l2: read_u16(20) as usize,
+            l3: read_u16(22) as usize,
+            output_buckets: bytes[24] as usize,
+        };
+
+        if arch.l2 > MAX_DENSE || arch.l3 > MAX_DENSE || (arch.l2 == 0 && arch.l3 != 0) {
+            return Err(format!("unsupported layer sizes {} and {}, at most {} and L3 needs L2", arch.l2, arch.l3, MAX_DENSE));
+        }
+        if !(1..=MAX_OUTPUT_BUCKETS).contains(&arch.output_buckets) {
+            return Err(format!("network has {} output buckets, expected 1 to {}", arch.output_buckets, MAX_OUTPUT_BUCKETS));
+        }
+
+        Ok(NetHeader { hidden_size, qa, qb, scale, layout, arch })
+    }
+}
 
-            // Not-Side-To-Move Accumulator -> Output.
-            for (&input, &weight) in them.vals.iter().zip(&self.output_weights[HIDDEN_SIZE..]) {
-                output += screlu(input) * i32::from(weight);
+/// Float layer, weights stored input-major for every output bucket
+struct DenseLayer {
+    inputs: usize,
+    outputs: usize,
+    weights: Vec<f32>,
+    bias: Vec<f32>,
+}
+
+impl DenseLayer {
+    fn forward(&self, bucket: usize, input: &[f32], output: &mut [f32]) {
+        output.copy_from_slice(&self.bias[bucket * self.outputs..][..self.outputs]);
+        let weights = &self.weights[bucket * self.inputs * self.outputs..];
+
+        // Adding whole rows lets the compiler vectorise over the outputs
+        for (i, &x) in input.iter().enumerate() {
+            if x != 0.0 {
+                for (out, &weight) in output.iter_mut().zip(&weights[i * self.outputs..][..self.outputs]) {
+                    *out += x * weight;
+                }
             }
         }
-        // Reduce quantization from QA * QA * QB to QA * QB.
-        output /= i32::from(QA);
+    }
+}
 
-        // Add bias.
-        output += i32::from(self.output_bias);
+enum OutputLayers {
+    /// L1 straight to the output, quantised with QB (bias with QA * QB)
+    Linear { weights: Vec<i16>, bias: Vec<i16> },
+    /// L1 -> L2 (-> L3) -> 1 in floats, SCReLU in between
+    Dense(Vec<DenseLayer>),
+}
+
+/// A loaded net. Every supported hidden size is its own variant,
+/// so accumulators and evaluation are compiled for it.
+// Only ever lives behind an `Arc`, the size difference doesn't matter
+#[allow(clippy::large_enum_variant)]
+pub enum Network {
+    H128(Weights<128>),
+    H256(Weights<256>),
+    H512(Weights<512>),
+    H768(Weights<768>),
+    H1024(Weights<1024>),
+}
+
+impl Network {
+    /// File size of a bare net with this layout. Bullet pads the file to a multiple of 64 bytes.
+    pub const fn file_size(layout: &InputLayout) -> usize {
+        Architecture::PLAIN.body_size(layout, HIDDEN_SIZE).next_multiple_of(64)
+    }
+
+    /// Network from the raw little endian i16s bullet writes out
+    pub fn from_bytes(bytes: &[u8], layout: InputLayout) -> Result<Arc<Network>, String> {
+        if bytes.len() != Self::file_size(&layout) {
+            return Err(format!("network has {} bytes, expected {}", bytes.len(), Self::file_size(&layout)));
+        }
+
+        Ok(Arc::new(Network::H256(Weights::read(bytes, &NetHeader::bare(layout))?)))
+    }
+
+    /// Network from a file starting with a header
+    pub fn from_bytes_with_header(bytes: &[u8]) -> Result<Arc<Network>, String> {
+        let header = NetHeader::parse(bytes)?;
+
+        if bytes.len() != header.file_size() {
+            return Err(format!("network has {} bytes, its header describes {}", bytes.len(), header.file_size()));
+        }
 
-        // Apply eval scale.
-        output *= SCALE;
+        let body = &bytes[NET_HEADER_SIZE..];
+        let network = match header.hidden_size {
+            128 => Network::H128(Weights::read(body, &header)?),
+            256 => Network::H256(Weights::read(body, &header)?),
+            512 => Network::H512(Weights::read(body, &header)?),
+            768 => Network::H768(Weights::read(body, &header)?),
+            1024 => Network::H1024(Weights::read(body, &header)?),
+            _ => unreachable!("header parsing only accepts HIDDEN_SIZES"),
+        };
+
+        Ok(Arc::new(network))
+    }
+
+    /// Load a network file, e.g. for the `EvalFile` option. Bare nets are told apart by their size.
+    pub fn load(path: &str) -> Result<Arc<Network>, String> {
+        let bytes = std::fs::read(path).map_err(|err| format!("failed to read '{}': {}", path, err))?;
 
-        // Remove quantisation altogether.
-        output /= i32::from(QA) * i32::from(QB);
+        if bytes.starts_with(NET_MAGIC) {
+            return Self::from_bytes_with_header(&bytes);
+        }
 
-        output
+        match InputLayout::KNOWN.iter().find(|layout| Self::file_size(layout) == bytes.len()) {
+            Some(layout) => Self::from_bytes(&bytes, *layout),
+            None => Err(format!(
+                "network has {} bytes, no known input layout has that size ({})",
+                bytes.len(),
+                InputLayout::KNOWN.iter().map(|layout| Self::file_size(layout).to_string()).collect::<Vec<_>>().join(", "),
+            )),
+        }
     }
 }
 
-#[inline]
-#[target_feature(enable = "avx2")]
-unsafe fn screlu_dot_avx2(
-    input: &[i16; HIDDEN_SIZE],
-    weights: &[i16; HIDDEN_SIZE],
-) -> i32 {
-    debug_assert_eq!(HIDDEN_SIZE % 16, 0);
+/// Weights of a net with `H` hidden neurons per perspective.
+pub struct Weights<const H: usize> {
+    layout: InputLayout,
+    arch: Architecture,
+    qa: i16,
+    qb: i16,
+    scale: i32,
+    /// Column-Major `H x (768 * buckets)` matrix.
+    /// Values have quantization of QA.
+    feature_weights: Vec<Accumulator<H>>,
+    /// Vector with dimension `H`.
+    /// Values have quantization of QA.
+    feature_bias: Accumulator<H>,
+    output: OutputLayers,
+}
 
-    let zero = _mm256_setzero_si256();
-    let qa = _mm256_set1_epi16(QA);
+impl<const H: usize> Weights<H> {
+    // Sizes are checked by the caller
+    fn read(bytes: &[u8], header: &NetHeader) -> Result<Weights<H>, String> {
+        debug_assert_eq!(header.hidden_size, H);
+        let arch = header.arch;
+        let layout = header.layout;
+
+        let mut values = bytes.chunks_exact(2).map(|value| i16::from_le_bytes([value[0], value[1]]));
+        let mut read_accumulator = || {
+            let mut accumulator = Accumulator { vals: [0; H] };
+            accumulator.vals.iter_mut().for_each(|val| *val = values.next().unwrap());
+            accumulator
+        };
+
+        let feature_weights = (0..768 * layout.bucket_count()).map(|_| read_accumulator()).collect();
+        let feature_bias = read_accumulator();
+
+        let output = if arch.l2 == 0 {
+            let weights: Vec<i16> = values.by_ref().take(arch.output_buckets * 2 * arch.l1_outputs(H)).collect();
+            let bias = values.by_ref().take(arch.output_buckets).collect();
+            Self::check_screlu_weights(&weights, header)?;
+            OutputLayers::Linear { weights, bias }
+        } else {
+            let float_start = (768 * layout.bucket_count() + 1) * H * 2;
+            let mut floats = bytes[float_start..].chunks_exact(4).map(|value| f32::from_le_bytes([value[0], value[1], value[2], value[3]]));
+            let dims: Vec<usize> = [2 * arch.l1_outputs(H), arch.l2, arch.l3, 1].into_iter().filter(|&dim| dim != 0).collect();
+
+            OutputLayers::Dense(dims.windows(2).map(|dims| {
+                let weights = floats.by_ref().take(arch.output_buckets * dims[0] * dims[1]).collect();
+                let bias = floats.by_ref().take(arch.output_buckets * dims[1]).collect();
+                DenseLayer { inputs: dims[0], outputs: dims[1], weights, bias }
+            }).collect())
+        };
+
+        Ok(Weights {
+            layout,
+            arch,
+            qa: header.qa,
+            qb: header.qb,
+            scale: header.scale,
+            feature_weights,
+            feature_bias,
+            output,
+        })
+    }
 
-    let mut sum = _mm256_setzero_si256();
+    // `simd::screlu_dot` computes activation * weight in i16, so that has to fit for every weight.
+    // Pairwise nets multiply in i32.
+    fn check_screlu_weights(weights: &[i16], header: &NetHeader) -> Result<(), String> {
+        if header.arch.pairwise {
+            return Ok(());
+        }
 
-    for i in (0..HIDDEN_SIZE).step_by(16) {
-        let x = _mm256_loadu_si256(
-            input.as_ptr().add(i) as *const __m256i
-        );
+        let limit = i32::from(i16::MAX) / i32::from(header.qa);
+        match weights.iter().find(|&&weight| i32::from(weight).abs() > limit) {
+            Some(weight) => Err(format!("output weight {} overflows with QA {}, weights have to be within {}", weight, header.qa, limit)),
+            None => Ok(()),
+        }
+    }
 
-        let w = _mm256_loadu_si256(
-            weights.as_ptr().add(i) as *const __m256i
-        );
+    pub fn layout(&self) -> &InputLayout {
+        &self.layout
+    }
 
-        // v = clamp(x, 0, QA)
-        let v = _mm256_min_epi16(
-            _mm256_max_epi16(x, zero),
-            qa,
-        );
+    pub fn architecture(&self) -> &Architecture {
+        &self.arch
+    }
+
+    /// Calculates the output of the network, starting from the already
+    /// calculated hidden layer (done efficiently during makemoves).
+    pub fn evaluate(&self, us: &Accumulator<H>, them: &Accumulator<H>, piece_count: u32) -> i32 {
+        let bucket = self.arch.output_bucket(piece_count);
+
+        match &self.output {
+            OutputLayers::Linear { weights, bias } => {
+                let mut output = self.linear_output(weights, bias, bucket, us, them);
+
+                // Apply eval scale.
+                output *= self.scale;
+
+                // Remove quantisation altogether.
+                output /= i32::from(self.qa) * i32::from(self.qb);
+
+                output
+            }
+            OutputLayers::Dense(layers) => (self.dense_output(layers, bucket, us, them) * self.scale as f32) as i32,
+        }
+    }
 
-        // Lizard SCReLU:
-        //
-        // (v * w) * v
-        //
-        // v * w remains in i16 because the weights are clipped.
-        let vw = _mm256_mullo_epi16(w, v);
+    /// What the net itself outputs, before the eval scale. A win probability in logits
+    /// for nets trained the usual way.
+    pub fn raw_output(&self, us: &Accumulator<H>, them: &Accumulator<H>, piece_count: u32) -> f32 {
+        let bucket = self.arch.output_bucket(piece_count);
+
+        match &self.output {
+            OutputLayers::Linear { weights, bias } => {
+                self.linear_output(weights, bias, bucket, us, them) as f32 / (f32::from(self.qa) * f32::from(self.qb))
+            }
+            OutputLayers::Dense(layers) => self.dense_output(layers, bucket, us, them),
+        }
+    }
+
+    // Quantised with QA * QB
+    fn linear_output(&self, weights: &[i16], bias: &[i16], bucket: usize, us: &Accumulator<H>, them: &Accumulator<H>) -> i32 {
+        let width = self.arch.l1_outputs(H);
+        let weights = &weights[bucket * 2 * width..][..2 * width];
+
+        let mut output = if self.arch.pairwise {
+            pairwise_dot(&us.vals, &weights[..width], self.qa) + pairwise_dot(&them.vals, &weights[width..], self.qa)
+        } else {
+            simd::screlu_dot(&us.vals, weights[..H].try_into().unwrap(), self.qa)
+                + simd::screlu_dot(&them.vals, weights[H..].try_into().unwrap(), self.qa)
+        };
+
+        // Reduce quantization from QA * QA * QB to QA * QB.
+        output /= i32::from(self.qa);
+
+        // Add bias.
+        output + i32::from(bias[bucket])
+    }
 
-        // Pair adjacent terms:
-        //
-        // vw[0]*v[0] + vw[1]*v[1]
-        // vw[2]*v[2] + vw[3]*v[3]
-        // ...
-        //
-        // Results are i32.
-        let products = _mm256_madd_epi16(vw, v);
+    fn dense_output(&self, layers: &[DenseLayer], bucket: usize, us: &Accumulator<H>, them: &Accumulator<H>) -> f32 {
+        let width = self.arch.l1_outputs(H);
+        // Both perspectives next to each other, later layers reuse the front
+        let mut buffer = [[0.0; H]; 2];
+        let input = buffer.as_flattened_mut();
+        self.activate_l1(us, &mut input[..width]);
+        self.activate_l1(them, &mut input[width..2 * width]);
+
+        let mut inputs = 2 * width;
+        let mut output = [0.0; MAX_DENSE];
+        for (n, layer) in layers.iter().enumerate() {
+            layer.forward(bucket, &input[..inputs], &mut output[..layer.outputs]);
+            inputs = layer.outputs;
+
+            let last = n + 1 == layers.len();
+            for (x, &y) in input.iter_mut().zip(&output[..inputs]) {
+                *x = if last { y } else { y.clamp(0.0, 1.0).powi(2) };
+            }
+        }
 
-        sum = _mm256_add_epi32(sum, products);
+        input[0]
     }
 
-    // Reduce eight i32 lanes to one i32.
-    let lo = _mm256_castsi256_si128(sum);
-    let hi = _mm256_extracti128_si256(sum, 1);
+    // SCReLU or pairwise products, scaled to 0.0 .. 1.0
+    fn activate_l1(&self, accumulator: &Accumulator<H>, output: &mut [f32]) {
+        let clipped = |x: i16| i32::from(x).clamp(0, i32::from(self.qa));
+        let qa = f32::from(self.qa);
+
+        if self.arch.pairwise {
+            let (left, right) = accumulator.vals.split_at(H / 2);
+            for ((out, &a), &b) in output.iter_mut().zip(left).zip(right) {
+                *out = (clipped(a) * clipped(b)) as f32 / (qa * qa);
+            }
+        } else {
+            for (out, &a) in output.iter_mut().zip(&accumulator.vals) {
+                *out = (clipped(a) as f32 / qa).powi(2);
+            }
+        }
+    }
+}
 
-    let sum128 = _mm_add_epi32(lo, hi);
-    let sum64 = _mm_hadd_epi32(sum128, sum128);
-    let result = _mm_hadd_epi32(sum64, sum64);
+/// Products of the accumulator halves times the weights, in QA * QA * QB like `screlu_dot`
+fn pairwise_dot<const H: usize>(input: &[i16; H], weights: &[i16], qa: i16) -> i32 {
+    let clipped = |x: i16| i32::from(x).clamp(0, i32::from(qa));
+    let (left, right) = input.split_at(H / 2);
 
-    _mm_cvtsi128_si32(result)
+    left.iter()
+        .zip(right)
+        .zip(weights)
+        .map(|((&a, &b), &w)| clipped(a) * clipped(b) * i32::from(w))
+        .sum()
 }
 
 /// A column of the feature-weights matrix.
 /// Note the `align(64)`.
 #[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(C, align(64))]
-pub struct Accumulator {
-    pub vals: [i16; HIDDEN_SIZE],
+pub struct Accumulator<const H: usize> {
+    pub vals: [i16; H],
 }
 
-impl Accumulator {
+impl<const H: usize> Accumulator<H> {
     /// Initialised with bias so we can just efficiently
     /// operate on it afterwards.
-    pub fn new(net: &Network) -> Self {
+    pub fn new(net: &Weights<H>) -> Self {
         net.feature_bias
     }
 
     /// Add a feature to an accumulator.
-    pub fn add_feature(&mut self, feature_idx: usize, net: &Network) {
-        for (i, d) in self.vals.iter_mut().zip(&net.feature_weights[feature_idx].vals) {
-            *i += *d
-        }
+    pub fn add_feature(&mut self, feature_idx: usize, net: &Weights<H>) {
+        simd::update(&mut self.vals, None, &[&net.feature_weights[feature_idx].vals], &[]);
     }
 
     /// Remove a feature from an accumulator.
-    pub fn remove_feature(&mut self, feature_idx: usize, net: &Network) {
-        for (i, d) in self.vals.iter_mut().zip(&net.feature_weights[feature_idx].vals) {
-            *i -= *d
-        }
+    pub fn remove_feature(&mut self, feature_idx: usize, net: &Weights<H>) {
+        simd::update(&mut self.vals, None, &[], &[&net.feature_weights[feature_idx].vals]);
+    }
+
+    /// `prev` with one feature added and one removed, written in a single pass.
+    pub fn add_sub(&mut self, prev: &Accumulator<H>, add: usize, sub: usize, net: &Weights<H>) {
+        let weights = &net.feature_weights;
+        simd::update(&mut self.vals, Some(&prev.vals), &[&weights[add].vals], &[&weights[sub].vals]);
+    }
+
+    /// `prev` with one feature added and two removed, written in a single pass.
+    pub fn add_sub_sub(&mut self, prev: &Accumulator<H>, add: usize, sub1: usize, sub2: usize, net: &Weights<H>) {
+        let weights = &net.feature_weights;
+        simd::update(&mut self.vals, Some(&prev.vals), &[&weights[add].vals], &[&weights[sub1].vals, &weights[sub2].vals]);
+    }
+
+    /// `prev` with two features added and two removed, written in a single pass.
+    pub fn add_sub_add_sub(&mut self, prev: &Accumulator<H>, add1: usize, sub1: usize, add2: usize, sub2: usize, net: &Weights<H>) {
+        let weights = &net.feature_weights;
+        simd::update(
+            &mut self.vals,
+            Some(&prev.vals),
+            &[&weights[add1].vals, &weights[add2].vals],
+            &[&weights[sub1].vals, &weights[sub2].vals],
+        );
     }
 }
 
-pub fn feature_index(piece: Piece, square: usize) -> usize {
+// Black sees the board flipped vertically, white with a1 = 0 (board squares start at a8)
+#[inline(always)]
+fn orient(perspective: Color, square: u8) -> u8 {
+    if perspective == White { square ^ 56 } else { square }
+}
+
+/// Input of `piece` on `square` for one perspective
+#[inline(always)]
+pub fn feature_index(perspective: Color, bucket: InputBucket, piece: Piece, square: u8) -> usize {
     debug_assert!(piece != Piece::NONE);
 
-    piece as usize * 64 + square
+    let piece = if perspective == White { piece } else { piece.flip_color() };
+    bucket.offset + piece as usize * 64 + (orient(perspective, square) ^ bucket.flip) as usize
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::evaluation::nnue::{HIDDEN_SIZE, screlu, screlu_dot_avx2};
+    use crate::evaluation::nnue::{
+        Accumulator, Architecture, EMBEDDED_NET, HIDDEN_SIZE, InputLayout, NET_HEADER_SIZE, NET_MAGIC, NET_VERSION,
+        NetHeader, Network, OutputLayers, Weights, embedded_network,
+    };
 
+    fn header_bytes(header: &NetHeader) -> Vec<u8> {
+        let mut bytes = vec![0; NET_HEADER_SIZE];
+        bytes[..8].copy_from_slice(NET_MAGIC);
+        bytes[8..10].copy_from_slice(&NET_VERSION.to_le_bytes());
+        bytes[10..12].copy_from_slice(&(header.hidden_size as u16).to_le_bytes());
+        bytes[12..14].copy_from_slice(&header.qa.to_le_bytes());
+        bytes[14..16].copy_from_slice(&header.qb.to_le_bytes());
+        bytes[16..18].copy_from_slice(&(header.scale as u16).to_le_bytes());
+        bytes[18] = InputLayout::KNOWN.iter().position(|layout| *layout == header.layout).unwrap() as u8;
+        bytes[19] = header.arch.pairwise as u8;
+        bytes[20..22].copy_from_slice(&(header.arch.l2 as u16).to_le_bytes());
+        bytes[22..24].copy_from_slice(&(header.arch.l3 as u16).to_le_bytes());
+        bytes[24] = header.arch.output_buckets as u8;
+        bytes
+    }
 
-#[test]
-fn test_screlu_avx2() {
-    let mut input = [0i16; HIDDEN_SIZE];
-    let mut weights = [0i16; HIDDEN_SIZE];
+    // Header and an all zero body, padded like bullet does
+    fn empty_net_file(header: &NetHeader) -> Vec<u8> {
+        let mut bytes = header_bytes(header);
+        bytes.resize(header.file_size(), 0);
+        bytes
+    }
 
-    // Ideally test lots of random values too.
-    for i in 0..HIDDEN_SIZE {
-        input[i] = ((i * 37) % 500) as i16 - 100;
-        weights[i] = ((i * 17) % 253) as i16 - 126;
+    fn weights_256(network: &Network) -> &Weights<256> {
+        let Network::H256(weights) = network else { panic!("expected a net with 256 hidden neurons") };
+        weights
     }
 
-    let scalar: i32 = input
-        .iter()
-        .zip(weights.iter())
-        .map(|(&x, &w)| screlu(x) * i32::from(w))
-        .sum();
+    #[test]
+    fn test_network_loading() {
+        let network = embedded_network();
+        let bias_offset = (769 * HIDDEN_SIZE + 2 * HIDDEN_SIZE) * 2;
+        let OutputLayers::Linear { bias, .. } = &weights_256(&network).output else { panic!("embedded net has no hidden layers") };
+        assert_eq!(bias[0], i16::from_le_bytes([EMBEDDED_NET[bias_offset], EMBEDDED_NET[bias_offset + 1]]));
+        assert_eq!(Network::file_size(&InputLayout::FLAT), EMBEDDED_NET.len());
+
+        // Truncated file
+        assert!(Network::from_bytes(&EMBEDDED_NET[1..], InputLayout::FLAT).is_err());
+        assert!(Network::from_bytes(EMBEDDED_NET, InputLayout::HM_4).is_err());
+        assert!(Network::load("/nonexistent/dual.nnue").is_err());
+    }
 
-    let simd = unsafe {
-        screlu_dot_avx2(&input, &weights)
-    };
+    #[test]
+    fn test_headered_network() {
+        // The embedded net behind a header with the bare net's parameters evaluates the same
+        let header = NetHeader::bare(InputLayout::FLAT);
+        let body_size = header.arch.body_size(&header.layout, header.hidden_size);
+        let mut bytes = header_bytes(&header);
+        bytes.extend_from_slice(&EMBEDDED_NET[..body_size]);
+        bytes.resize(header.file_size(), 0);
+
+        let embedded = embedded_network();
+        let headered = Network::from_bytes_with_header(&bytes).unwrap();
+        let (embedded, headered) = (weights_256(&embedded), weights_256(&headered));
+        let mut us = Accumulator::new(embedded);
+        let mut them = Accumulator::new(embedded);
+        us.add_feature(100, embedded);
+        them.add_feature(500, embedded);
+        assert_eq!(embedded.evaluate(&us, &them, 32), headered.evaluate(&us, &them, 32));
+
+        // Every broken field is reported instead of read as weights
+        let broken = |at: usize, value: u8| {
+            let mut bytes = bytes.clone();
+            bytes[at] = value;
+            Network::from_bytes_with_header(&bytes).err().unwrap()
+        };
+        assert!(broken(8, 1).contains("version"));
+        assert!(broken(10, 100).contains("hidden size"));
+        assert!(broken(11, 2).contains("bytes"));
+        assert!(broken(12, 0).contains("QA"));
+        assert!(broken(14, 0).contains("quantisation"));
+        assert!(broken(18, 9).contains("feature set"));
+        assert!(broken(19, 2).contains("pairwise"));
+        assert!(broken(24, 0).contains("output buckets"));
+        assert!(Network::from_bytes_with_header(&bytes[..bytes.len() - 64]).is_err());
+        assert!(Network::from_bytes_with_header(&bytes[1..]).is_err());
+    }
 
-    assert_eq!(scalar, simd);
-}
+    #[test]
+    fn test_hidden_size_dispatch() {
+        for hidden_size in [128, 512, 768, 1024] {
+            let header = NetHeader { hidden_size, qa: 181, qb: 128, scale: 300, ..NetHeader::bare(InputLayout::HM_4) };
+            let network = Network::from_bytes_with_header(&empty_net_file(&header)).unwrap();
+
+            let matches = match &*network {
+                Network::H128(weights) => hidden_size == 128 && weights.qa == 181 && weights.scale == 300,
+                Network::H512(weights) => hidden_size == 512 && weights.qb == 128,
+                Network::H768(weights) => hidden_size == 768 && weights.layout == InputLayout::HM_4,
+                Network::H1024(_) => hidden_size == 1024,
+                Network::H256(_) => false,
+            };
+            assert!(matches, "hidden size {hidden_size}");
+        }
+    }
+
+    #[test]
+    fn test_quantisation_limits() {
+        // Oversized QA in an otherwise valid header
+        let header = NetHeader::bare(InputLayout::FLAT);
+        let mut bytes = empty_net_file(&header);
+        bytes[12..14].copy_from_slice(&256i16.to_le_bytes());
+        assert!(Network::from_bytes_with_header(&bytes).err().unwrap().contains("QA 256"));
+        bytes[12..14].copy_from_slice(&(-5i16).to_le_bytes());
+        assert!(Network::from_bytes_with_header(&bytes).is_err());
+
+        // QA 181 leaves room for output weights up to 181
+        let header = NetHeader { qa: 181, ..NetHeader::bare(InputLayout::FLAT) };
+        let output_start = NET_HEADER_SIZE + 769 * HIDDEN_SIZE * 2;
+        let mut bytes = empty_net_file(&header);
+        bytes[output_start..][..2].copy_from_slice(&181i16.to_le_bytes());
+        assert!(Network::from_bytes_with_header(&bytes).is_ok());
+        bytes[output_start..][..2].copy_from_slice(&(-182i16).to_le_bytes());
+        assert!(Network::from_bytes_with_header(&bytes).err().unwrap().contains("overflows"));
+
+        // Pairwise nets don't go through the i16 kernels
+        let pairwise = NetHeader { arch: Architecture { pairwise: true, ..Architecture::PLAIN }, ..header };
+        let mut bytes = empty_net_file(&pairwise);
+        bytes[output_start..][..2].copy_from_slice(&(-182i16).to_le_bytes());
+        assert!(Network::from_bytes_with_header(&bytes).is_ok());
+    }
+
+    #[test]
+    fn test_output_buckets() {
+        // Everything zero except the output biases, which tell the buckets apart
+        let linear = NetHeader { arch: Architecture { l2: 0, l3: 0, pairwise: true, output_buckets: 8 }, ..NetHeader::bare(InputLayout::FLAT) };
+        let mut bytes = empty_net_file(&linear);
+        let bias_start = NET_HEADER_SIZE + linear.arch.body_size(&linear.layout, linear.hidden_size) - 8 * 2;
+        for bucket in 0..8 {
+            // 10 centipawns per bucket after scaling
+            bytes[bias_start + bucket * 2..][..2].copy_from_slice(&(408 * bucket as i16).to_le_bytes());
+        }
+
+        let network = Network::from_bytes_with_header(&bytes).unwrap();
+        let net = weights_256(&network);
+        let acc = Accumulator::new(net);
+        assert_eq!(net.evaluate(&acc, &acc, 2), 0);
+        assert_eq!(net.evaluate(&acc, &acc, 10), 20);
+        assert_eq!(net.evaluate(&acc, &acc, 32), 70);
+
+        let dense = NetHeader { arch: Architecture { l2: 16, l3: 32, pairwise: true, output_buckets: 8 }, ..NetHeader::bare(InputLayout::FLAT) };
+        let mut bytes = empty_net_file(&dense);
+        let bias_start = NET_HEADER_SIZE + dense.arch.body_size(&dense.layout, dense.hidden_size) - 8 * 4;
+        for bucket in 0..8 {
+            bytes[bias_start + bucket * 4..][..4].copy_from_slice(&(0.25 * bucket as f32).to_le_bytes());
+        }
+
+        let network = Network::from_bytes_with_header(&bytes).unwrap();
+        let net = weights_256(&network);
+        let acc = Accumulator::new(net);
+        assert_eq!(net.evaluate(&acc, &acc, 2), 0);
+        assert_eq!(net.evaluate(&acc, &acc, 10), 200);
+        assert_eq!(net.evaluate(&acc, &acc, 32), 700);
+    }
 
 }
-// #[cfg(test)]
-// mod tests {
-//     use crate::{nnue::{Accumulator, Network, screlu}, shared::{KIWIPETE, START_POSITION}, types::board::BoardPosition};
-
-    
-// pub static NNUE: Network =
-//     unsafe { std::mem::transmute(*include_bytes!("../nets/beans.bin")) };
-
-//     #[test]
-//     pub fn test_nnue_loading() {
-//         println!("{}", std::mem::size_of::<Network>()); 
-//         println!("{}", include_bytes!("../nets/rival-256x2.bin").len());
-//         assert_eq!(std::mem::size_of::<Network>(), include_bytes!("../nets/rival-256x2.bin").len());
-        
-
-//         println!("{:?}", NNUE.feature_bias);
-//         println!("{:?}", NNUE.output_bias);
-//         println!("{:?}", NNUE.output_weights);
-
-//     }
-
-//         #[test]
-//     pub fn test_beans_loading() {  
-//         assert_eq!(NNUE.feature_bias.vals[0..16], [176 as i16, 33, 18, 47, 9, 64, 104, -24, 161, 85, 58, 180, 23, 57, 6, 36]);
-//         assert_eq!(NNUE.output_bias, 825);
-        
-//         let mut start_pos : BoardPosition = BoardPosition::new(START_POSITION);
-//         start_pos.refresh_nnue(&NNUE);
-
-//         assert_eq!([-1233 as i16, 106, 168, -515, 401, 268, 5, 134, 565, 564, -26, 233, -346, 253, 131, 237], start_pos.accumulators[0].vals[0..16]);
-//         assert_eq!([-1233 as i16, 106, 168, -515, 401, 268, 5, 134, 565, 564, -26, 233, -346, 253, 131, 237], start_pos.accumulators[1].vals[0..16]);
-
-//         println!("-------------------------------");
-//         let mut kiwipete : BoardPosition = BoardPosition::new(KIWIPETE);
-//         kiwipete.refresh_nnue(&NNUE);
-    
-//         assert_eq!([-1326 as i16, 140, 57, -500, 539, 265, -180, 81, 574, 576, 42, 271, -260, 286, -52, 287], kiwipete.accumulators[0].vals[0..16]);
-//         assert_eq!([-1296 as i16, 138, 83, -485, 511, 229, 7, 97, 575, 565, 2, -174, -279, 285, 153, 303], kiwipete.accumulators[1].vals[0..16]);
-
-//     }
-
-//     #[test]
-//     pub fn test_empty_acc() {
-//         let acc = Accumulator::new(&NNUE);
-
-//         let ev = NNUE.evaluate(&acc, &acc);
-//         println!("{}", ev);
-//         assert!(ev < 100);
-//         assert!(ev > 0);
-//     }
-
-//     #[test]
-//     pub fn test_validate_screlu() {
-//         assert_eq!(0, screlu(-1));    // 0
-//         assert_eq!(0, screlu(0));     // 0
-//         assert_eq!(1, screlu(1));     // 1
-//         assert_eq!(65025, screlu(255));   // 65025
-//         assert_eq!(65025, screlu(300));   // 65025
-//     }
-// }
-
-
-// beans
-// Test positions
-// startpos: rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1
-// kiwipete: r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1
-// Network parameters
-// HL=64
-// activation=SCReLU
-// QA=255, QB=64
-// Scale=400
-// File IO
-// First 16 HL biases: 176 33 18 47 9 64 104 -24 161 85 58 180 23 57 6 36
-// Output neuron bias: 825
-// Active indices
-// startpos (both perspectives): 192 65 130 259 324 133 70 199 8 9 10 11 12 13 14 15 432 433 434 435 436 437 438 439 632 505 570 699 764 573 510 639
-// kiwipete (white): 192 324 199 8 9 10 139 140 13 14 15 82 277 407 409 28 35 100 552 489 428 493 430 432 434 435 692 437 566 632 764 639
-// kiwipete (black): 632 764 639 432 433 434 563 564 437 438 439 490 685 47 33 420 411 476 144 81 20 85 22 8 10 11 268 13 142 192 324 199
-// First 16 accumulator values (pre-activation)
-// startpos (both perspectives): -1233 106 168 -515 401 268 5 134 565 564 -26 233 -346 253 131 237
-// kiwipete (white): -1326 140 57 -500 539 265 -180 81 574 576 42 271 -260 286 -52 287
-// kiwipete (black): -1296 138 83 -485 511 229 7 97 575 565 2 -174 -279 285 153 303
-// Unscaled eval without output bias
-// startpos: 608404
-// kiwipete: -1423747
-// Final evaluation
-// startpos: 78
-// kiwipete: -116
\ No newline at end of file
@@ -0,0 +1,428 @@
+use std::sync::OnceLock;
+
+
+/// Vector instruction set the NNUE kernels run on. Picked once, the first time a kernel is used.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    Avx512,
+    Avx2,
+    Sse41,
+    Neon,
+    Scalar,
+}
+
+static BACKEND: OnceLock<Backend> = OnceLock::new();
+
+/// Backend every kernel dispatches to
+#[inline(always)]
+pub fn backend() -> Backend {
+    *BACKEND.get_or_init(Backend::detect)
+}
+
+impl Backend {
+    /// Widest backend the CPU supports
+    pub fn detect() -> Backend {
+        Backend::ALL.into_iter().find(|backend| backend.is_supported()).unwrap_or(Backend::Scalar)
+    }
+
+    /// From the widest down
+    pub const ALL: [Backend; 5] = [Backend::Avx512, Backend::Avx2, Backend::Sse41, Backend::Neon, Backend::Scalar];
+
+    pub fn is_supported(self) -> bool {
+        match self {
+            #[cfg(target_arch = "x86_64")]
+            Backend::Avx512 => is_x86_feature_detected!("avx512f") && is_x86_feature_detected!("avx512bw"),
+            #[cfg(target_arch = "x86_64")]
+            Backend::Avx2 => is_x86_feature_detected!("avx2"),
+            #[cfg(target_arch = "x86_64")]
+            Backend::Sse41 => is_x86_feature_detected!("sse4.1"),
+            // NEON is part of the aarch64 baseline
+            #[cfg(target_arch = "aarch64")]
+            Backend::Neon => true,
+            Backend::Scalar => true,
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+    }
+}
+
+/// Sum of `screlu(input) * weight`, still in QA * QA * QB
+pub fn screlu_dot<const N: usize>(input: &[i16; N], weights: &[i16; N], qa: i16) -> i32 {
+    screlu_dot_on(backend(), input, weights, qa)
+}
+
+/// `out = prev + adds - subs` in a single pass, `prev` of `None` updates `out` in place
+pub fn update<const N: usize>(out: &mut [i16; N], prev: Option<&[i16; N]>, adds: &[&[i16; N]], subs: &[&[i16; N]]) {
+    update_on(backend(), out, prev, adds, subs)
+}
+
+#[inline(always)]
+fn screlu_dot_on<const N: usize>(backend: Backend, input: &[i16; N], weights: &[i16; N], qa: i16) -> i32 {
+    debug_assert!(backend.is_supported());
+    // Every backend works on whole 512 bit vectors
+    debug_assert_eq!(N % 32, 0);
+
+    // Safety: only supported backends are ever selected
+    unsafe {
+        match backend {
+            #[cfg(target_arch = "x86_64")]
+            Backend::Avx512 => x86::screlu_dot_avx512(input, weights, qa),
+            #[cfg(target_arch = "x86_64")]
+            Backend::Avx2 => x86::screlu_dot_avx2(input, weights, qa),
+            #[cfg(target_arch = "x86_64")]
+            Backend::Sse41 => x86::screlu_dot_sse41(input, weights, qa),
+            #[cfg(target_arch = "aarch64")]
+            Backend::Neon => neon::screlu_dot_neon(input, weights, qa),
+            _ => screlu_dot_scalar(input, weights, qa),
+        }
+    }
+}
+
+#[inline(always)]
+fn update_on<const N: usize>(backend: Backend, out: &mut [i16; N], prev: Option<&[i16; N]>, adds: &[&[i16; N]], subs: &[&[i16; N]]) {
+    debug_assert!(backend.is_supported());
+    debug_assert_eq!(N % 32, 0);
+
+    let out = out.as_mut_ptr();
+    // Every lane is read before it's written, so `prev` may be `out` itself
+    let prev = prev.map_or(out as *const i16, |prev| prev.as_ptr());
+
+    // Safety: only supported backends are ever selected, both pointers cover N values
+    unsafe {
+        match backend {
+            #[cfg(target_arch = "x86_64")]
+            Backend::Avx512 => x86::update_avx512(out, prev, adds, subs),
+            #[cfg(target_arch = "x86_64")]
+            Backend::Avx2 => x86::update_avx2(out, prev, adds, subs),
+            #[cfg(target_arch = "x86_64")]
+            Backend::Sse41 => x86::update_sse41(out, prev, adds, subs),
+            #[cfg(target_arch = "aarch64")]
+            Backend::Neon => neon::update_neon(out, prev, adds, subs),
+            _ => update_scalar(out, prev, adds, subs),
+        }
+    }
+}
+
+#[inline]
+/// Square Clipped ReLU - Activation Function.
+/// Note that this takes the i16s in the accumulator to i32s.
+/// Range is 0.0 .. 1.0 (in other words, 0 to QA*QA quantized).
+fn screlu(x: i16, qa: i16) -> i32 {
+    let y = i32::from(x).clamp(0, i32::from(qa));
+    y * y
+}
+
+fn screlu_dot_scalar<const N: usize>(input: &[i16; N], weights: &[i16; N], qa: i16) -> i32 {
+    input
+        .iter()
+        .zip(weights)
+        .map(|(&x, &w)| screlu(x, qa) * i32::from(w))
+        .sum()
+}
+
+// Wraps like the vector backends, so all of them agree even on overflow
+unsafe fn update_scalar<const N: usize>(out: *mut i16, prev: *const i16, adds: &[&[i16; N]], subs: &[&[i16; N]]) {
+    for i in 0..N {
+        let mut value = *prev.add(i);
+
+        for add in adds {
+            value = value.wrapping_add(add[i]);
+        }
+        for sub in subs {
+            value = value.wrapping_sub(sub[i]);
+        }
+
+        *out.add(i) = value;
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    #[target_feature(enable = "avx512f,avx512bw")]
+    pub unsafe fn screlu_dot_avx512<const N: usize>(input: &[i16; N], weights: &[i16; N], qa: i16) -> i32 {
+        debug_assert_eq!(N % 32, 0);
+
+        let zero = _mm512_setzero_si512();
+        let qa = _mm512_set1_epi16(qa);
+        let mut sum = _mm512_setzero_si512();
+
+        for i in (0..N).step_by(32) {
+            let x = _mm512_loadu_si512(input.as_ptr().add(i) as *const __m512i);
+            let w = _mm512_loadu_si512(weights.as_ptr().add(i) as *const __m512i);
+
+            let v = _mm512_min_epi16(_mm512_max_epi16(x, zero), qa);
+            // Same trick as AVX2, v * w fits in i16
+            let vw = _mm512_mullo_epi16(w, v);
+            sum = _mm512_add_epi32(sum, _mm512_madd_epi16(vw, v));
+        }
+
+        _mm512_reduce_add_epi32(sum)
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn screlu_dot_avx2<const N: usize>(
+        input: &[i16; N],
+        weights: &[i16; N],
+        qa: i16,
+    ) -> i32 {
+        debug_assert_eq!(N % 16, 0);
+
+        let zero = _mm256_setzero_si256();
+        let qa = _mm256_set1_epi16(qa);
+
+        let mut sum = _mm256_setzero_si256();
+
+        for i in (0..N).step_by(16) {
+            let x = _mm256_loadu_si256(
+                input.as_ptr().add(i) as *const __m256i
+            );
+
+            let w = _mm256_loadu_si256(
+                weights.as_ptr().add(i) as *const __m256i
+            );
+
+            // v = clamp(x, 0, QA)
+            let v = _mm256_min_epi16(
+                _mm256_max_epi16(x, zero),
+                qa,
+            );
+
+            // Lizard SCReLU:
+            //
+            // (v * w) * v
+            //
+            // v * w remains in i16 because the weights are clipped.
+            let vw = _mm256_mullo_epi16(w, v);
+
+            // Pair adjacent terms:
+            //
+            // vw[0]*v[0] + vw[1]*v[1]
+            // vw[2]*v[2] + vw[3]*v[3]
+            // ...
+            //
+            // Results are i32.
+            let products = _mm256_madd_epi16(vw, v);
+
+            sum = _mm256_add_epi32(sum, products);
+        }
+
+        // Reduce eight i32 lanes to one i32.
+        let lo = _mm256_castsi256_si128(sum);
+        let hi = _mm256_extracti128_si256(sum, 1);
+
+        let sum128 = _mm_add_epi32(lo, hi);
+        let sum64 = _mm_hadd_epi32(sum128, sum128);
+        let result = _mm_hadd_epi32(sum64, sum64);
+
+        _mm_cvtsi128_si32(result)
+    }
+
+    #[target_feature(enable = "sse4.1")]
+    pub unsafe fn screlu_dot_sse41<const N: usize>(input: &[i16; N], weights: &[i16; N], qa: i16) -> i32 {
+        debug_assert_eq!(N % 8, 0);
+
+        let zero = _mm_setzero_si128();
+        let qa = _mm_set1_epi16(qa);
+        let mut sum = _mm_setzero_si128();
+
+        for i in (0..N).step_by(8) {
+            let x = _mm_loadu_si128(input.as_ptr().add(i) as *const __m128i);
+            let w = _mm_loadu_si128(weights.as_ptr().add(i) as *const __m128i);
+
+            let v = _mm_min_epi16(_mm_max_epi16(x, zero), qa);
+            let vw = _mm_mullo_epi16(w, v);
+            sum = _mm_add_epi32(sum, _mm_madd_epi16(vw, v));
+        }
+
+        // Reduce four i32 lanes to one i32.
+        let sum64 = _mm_add_epi32(sum, _mm_shuffle_epi32(sum, 0b01_00_11_10));
+        let sum32 = _mm_add_epi32(sum64, _mm_shuffle_epi32(sum64, 0b10_11_00_01));
+
+        _mm_cvtsi128_si32(sum32)
+    }
+
+    #[target_feature(enable = "avx512f,avx512bw")]
+    pub unsafe fn update_avx512<const N: usize>(out: *mut i16, prev: *const i16, adds: &[&[i16; N]], subs: &[&[i16; N]]) {
+        for i in (0..N).step_by(32) {
+            let mut value = _mm512_loadu_si512(prev.add(i) as *const __m512i);
+
+            for add in adds {
+                value = _mm512_add_epi16(value, _mm512_loadu_si512(add.as_ptr().add(i) as *const __m512i));
+            }
+            for sub in subs {
+                value = _mm512_sub_epi16(value, _mm512_loadu_si512(sub.as_ptr().add(i) as *const __m512i));
+            }
+
+            _mm512_storeu_si512(out.add(i) as *mut __m512i, value);
+        }
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn update_avx2<const N: usize>(out: *mut i16, prev: *const i16, adds: &[&[i16; N]], subs: &[&[i16; N]]) {
+        for i in (0..N).step_by(16) {
+            let mut value = _mm256_loadu_si256(prev.add(i) as *const __m256i);
+
+            for add in adds {
+                value = _mm256_add_epi16(value, _mm256_loadu_si256(add.as_ptr().add(i) as *const __m256i));
+            }
+            for sub in subs {
+                value = _mm256_sub_epi16(value, _mm256_loadu_si256(sub.as_ptr().add(i) as *const __m256i));
+            }
+
+            _mm256_storeu_si256(out.add(i) as *mut __m256i, value);
+        }
+    }
+
+    #[target_feature(enable = "sse4.1")]
+    pub unsafe fn update_sse41<const N: usize>(out: *mut i16, prev: *const i16, adds: &[&[i16; N]], subs: &[&[i16; N]]) {
+        for i in (0..N).step_by(8) {
+            let mut value = _mm_loadu_si128(prev.add(i) as *const __m128i);
+
+            for add in adds {
+                value = _mm_add_epi16(value, _mm_loadu_si128(add.as_ptr().add(i) as *const __m128i));
+            }
+            for sub in subs {
+                value = _mm_sub_epi16(value, _mm_loadu_si128(sub.as_ptr().add(i) as *const __m128i));
+            }
+
+            _mm_storeu_si128(out.add(i) as *mut __m128i, value);
+        }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use std::arch::aarch64::*;
+
+    pub unsafe fn screlu_dot_neon<const N: usize>(input: &[i16; N], weights: &[i16; N], qa: i16) -> i32 {
+        debug_assert_eq!(N % 8, 0);
+
+        let zero = vdupq_n_s16(0);
+        let qa = vdupq_n_s16(qa);
+        let mut sum = vdupq_n_s32(0);
+
+        for i in (0..N).step_by(8) {
+            let x = vld1q_s16(input.as_ptr().add(i));
+            let w = vld1q_s16(weights.as_ptr().add(i));
+
+            let v = vminq_s16(vmaxq_s16(x, zero), qa);
+            let vw = vmulq_s16(w, v);
+            // Widening multiply-accumulate of both halves into i32 lanes
+            sum = vmlal_s16(sum, vget_low_s16(vw), vget_low_s16(v));
+            sum = vmlal_high_s16(sum, vw, v);
+        }
+
+        vaddvq_s32(sum)
+    }
+
+    pub unsafe fn update_neon<const N: usize>(out: *mut i16, prev: *const i16, adds: &[&[i16; N]], subs: &[&[i16; N]]) {
+        for i in (0..N).step_by(8) {
+            let mut value = vld1q_s16(prev.add(i));
+
+            for add in adds {
+                value = vaddq_s16(value, vld1q_s16(add.as_ptr().add(i)));
+            }
+            for sub in subs {
+                value = vsubq_s16(value, vld1q_s16(sub.as_ptr().add(i)));
+            }
+
+            vst1q_s16(out.add(i), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::evaluation::nnue::HIDDEN_SIZE;
+    use crate::evaluation::simd::{Backend, screlu_dot_on, update_on};
+
+    const QA: i16 = 255;
+
+    fn test_vectors<const N: usize>() -> ([i16; N], [i16; N]) {
+        let mut input = [0i16; N];
+        let mut weights = [0i16; N];
+
+        // Ideally test lots of random values too.
+        for i in 0..N {
+            input[i] = ((i * 37) % 500) as i16 - 100;
+            weights[i] = ((i * 17) % 253) as i16 - 126;
+        }
+
+        (input, weights)
+    }
+
+    fn check_screlu_dot<const N: usize>() {
+        let (input, weights) = test_vectors::<N>();
+        let scalar = screlu_dot_on(Backend::Scalar, &input, &weights, QA);
+
+        for backend in Backend::ALL.into_iter().filter(|backend| backend.is_supported()) {
+            assert_eq!(scalar, screlu_dot_on(backend, &input, &weights, QA), "{backend:?} with {N}");
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_screlu_avx2() {
+        if !Backend::Avx2.is_supported() {
+            return;
+        }
+
+        let (input, weights) = test_vectors::<HIDDEN_SIZE>();
+        let scalar = screlu_dot_on(Backend::Scalar, &input, &weights, QA);
+        let simd = screlu_dot_on(Backend::Avx2, &input, &weights, QA);
+
+        assert_eq!(scalar, simd);
+    }
+
+    #[test]
+    fn test_screlu_dot_backends() {
+        check_screlu_dot::<128>();
+        check_screlu_dot::<256>();
+        check_screlu_dot::<768>();
+        check_screlu_dot::<1024>();
+
+        // Quantisation comes from the net
+        let (input, weights) = test_vectors::<HIDDEN_SIZE>();
+        let scalar = screlu_dot_on(Backend::Scalar, &input, &weights, 181);
+        for backend in Backend::ALL.into_iter().filter(|backend| backend.is_supported()) {
+            assert_eq!(scalar, screlu_dot_on(backend, &input, &weights, 181), "{backend:?}");
+        }
+    }
+
+    #[test]
+    fn test_update_backends() {
+        let (prev, add1) = test_vectors::<HIDDEN_SIZE>();
+        let add2 = add1.map(|x| x / 3);
+        let sub1 = prev.map(|x| x / 2 - 7);
+        let sub2 = add1.map(|x| 40 - x / 5);
+
+        let mut scalar = [0i16; HIDDEN_SIZE];
+        update_on(Backend::Scalar, &mut scalar, Some(&prev), &[&add1, &add2], &[&sub1, &sub2]);
+
+        for backend in Backend::ALL.into_iter().filter(|backend| backend.is_supported()) {
+            let mut out = [0i16; HIDDEN_SIZE];
+            update_on(backend, &mut out, Some(&prev), &[&add1, &add2], &[&sub1, &sub2]);
+            assert_eq!(scalar, out, "{backend:?}");
+
+            // In place
+            let mut out = prev;
+            update_on(backend, &mut out, None, &[&add1, &add2], &[&sub1, &sub2]);
+            assert_eq!(scalar, out, "{backend:?}");
+        }
+
+        // Overflowing updates wrap on every backend
+        let high = [i16::MAX - 50; HIDDEN_SIZE];
+        let low = [i16::MIN + 50; HIDDEN_SIZE];
+        let mut scalar = [0i16; HIDDEN_SIZE];
+        update_on(Backend::Scalar, &mut scalar, Some(&high), &[&add1], &[&low]);
+
+        for backend in Backend::ALL.into_iter().filter(|backend| backend.is_supported()) {
+            let mut out = [0i16; HIDDEN_SIZE];
+            update_on(backend, &mut out, Some(&high), &[&add1], &[&low]);
+            assert_eq!(scalar, out, "{backend:?}");
+        }
+    }
+}
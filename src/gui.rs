@@ -1,5 +1,5 @@
-use crate::evaluation::nnue::NNUE;
-use crate::movegen::move_gen::generate_all_moves;
+use crate::evaluation::nnue::{Network, embedded_network};
+use crate::movegen::move_gen::generate_legal_moves;
 use crate::primitives::board::BoardPosition;
 use crate::movegen::perft::perft;
 use crate::search::{search};
@@ -9,9 +9,19 @@ use crate::primitives::shared::Color::{Black, White};
 use crate::primitives::shared::{KIWIPETE, Move, START_POSITION, coordinates_to_squares};
 use crate::primitives::shared::Piece::{B, N, Q, R};
 
+// Long algebraic notation, e.g. e2e4 or e7e8q
+fn is_move_notation(word: &str) -> bool {
+    let bytes = word.as_bytes();
+
+    (bytes.len() == 4 || bytes.len() == 5)
+        && (b'a'..=b'h').contains(&bytes[0]) && (b'1'..=b'8').contains(&bytes[1])
+        && (b'a'..=b'h').contains(&bytes[2]) && (b'1'..=b'8').contains(&bytes[3])
+        && bytes.get(4).is_none_or(|piece| b"qrbn".contains(piece))
+}
+
 pub fn parse_move(board: &BoardPosition, move_to_parse: &str) -> Option<Move> {
 
-    let legal_moves = generate_all_moves(board);
+    let legal_moves = generate_legal_moves(board);
 
     let src = coordinates_to_squares(&move_to_parse[0..2]);
     let target = coordinates_to_squares(&move_to_parse[2..4]);
@@ -42,6 +52,7 @@ pub fn parse_move(board: &BoardPosition, move_to_parse: &str) -> Option<Move> {
 
 pub fn parse_go(board_position: &BoardPosition, search_state: &mut SearchState, command: &str) {        
     search_state.stop_condition.reset();
+    search_state.search_moves.clear();
 
     let words : Vec<&str> = command.split_ascii_whitespace().collect();
     let mut wtime : Option<u64> = None;
@@ -49,19 +60,34 @@ pub fn parse_go(board_position: &BoardPosition, search_state: &mut SearchState,
     let mut winc : Option<u64> = None;
     let mut binc : Option<u64> = None;
 
-    for i in 0..words.len()/2 {
-        match words[2 * i + 1] {
-            "depth" => search_state.stop_condition.depth = Some(words[2*i+2].parse().unwrap_or(6)),
-            "perft" => {perft(board_position, words[2*i+2].parse().unwrap_or(4)); return;},
-            "wtime" => wtime = Some(words[2*i+2].parse().unwrap_or(1000)),
-            "btime" => btime = Some(words[2*i+2].parse().unwrap_or(1000)),
-            "winc" => winc = Some(words[2*i+2].parse().unwrap_or(1000)),
-            "binc" => binc = Some(words[2*i+2].parse().unwrap_or(1000)),
-            "softnodes" => search_state.stop_condition.soft_nodecount = Some(words[2*i+2].parse().unwrap_or(1000)),
-            "nodes" => search_state.stop_condition.hard_nodecount = Some(words[2*i+2].parse().unwrap_or(1000)),
-            "movetime" => search_state.stop_condition.movetime_deadline = Some(words[2*i+2].parse().unwrap_or(1000)),
+    let mut i = 1;
+    while i < words.len() {
+        let value = words.get(i + 1).copied().unwrap_or_default();
+        let takes_value = !matches!(words[i], "infinite" | "ponder" | "searchmoves");
+
+        match words[i] {
+            "infinite" => search_state.stop_condition.infinite = true,
+            // The pondering flag itself is raised by the UCI thread before the search starts
+            "ponder" => (),
+            // Every following word that is a legal move restricts the root
+            "searchmoves" => {
+                while let Some(mv) = words.get(i + 1).filter(|word| is_move_notation(word)).and_then(|word| parse_move(board_position, word)) {
+                    search_state.search_moves.push(mv);
+                    i += 1;
+                }
+            },
+            "depth" => search_state.stop_condition.depth = Some(value.parse().unwrap_or(6)),
+            "perft" => {perft(board_position, value.parse().unwrap_or(4), search_state.engine_config.threads, search_state.engine_config.hash); return;},
+            "wtime" => wtime = Some(value.parse().unwrap_or(1000)),
+            "btime" => btime = Some(value.parse().unwrap_or(1000)),
+            "winc" => winc = Some(value.parse().unwrap_or(1000)),
+            "binc" => binc = Some(value.parse().unwrap_or(1000)),
+            "softnodes" => search_state.stop_condition.soft_nodecount = Some(value.parse().unwrap_or(1000)),
+            "nodes" => search_state.stop_condition.hard_nodecount = Some(value.parse().unwrap_or(1000)),
+            "movetime" => search_state.stop_condition.movetime_deadline = Some(value.parse().unwrap_or(1000)),
             _ => ()
         }
+        i += if takes_value { 2 } else { 1 };
     }
 
     search_state.stop_condition.our_time_ms = if board_position.side == Black { btime } else { wtime };
@@ -145,7 +171,7 @@ pub fn parse_position_command(search_state: &mut SearchState, command: &str) ->
         }
 
         search_state.ply = 0;
-        search_state.network_state.start_board(&board_position, &NNUE);
+        search_state.network_state.start_board(&board_position, &search_state.engine_config.network);
 
         board_position
 }
@@ -175,6 +201,30 @@ pub fn parse_setoption(engine_config: &mut EngineConfig, command: &str) {
                 engine_config.soft_nodes = if soft_nodes == 0 { None } else { Some(soft_nodes) };
             }
         },
+        "Threads" => {
+            let val = words[4..].concat();
+            let parse_result = val.parse::<usize>();
+            if let Ok(threads) = parse_result {
+                engine_config.threads = threads.clamp(1, 256);
+            }
+        },
+        "EvalFile" => {
+            let path = words[4..].join(" ");
+            let network = if path.is_empty() || path == "<embedded>" { Ok(embedded_network()) } else { Network::load(&path) };
+
+            // A bad file keeps the previous net
+            match network {
+                Ok(network) => engine_config.network = network,
+                Err(err) => println!("info string EvalFile: {}", err),
+            }
+        },
+        "MultiPV" => {
+            let val = words[4..].concat();
+            let parse_result = val.parse::<usize>();
+            if let Ok(multi_pv) = parse_result {
+                engine_config.multi_pv = multi_pv.clamp(1, 256);
+            }
+        },
         _ => (),
     }
 
@@ -189,7 +239,9 @@ mod tests {
     use crate::primitives::shared::{START_POSITION};
     use crate::primitives::board::BoardPosition;
     use crate::search_objs::config::EngineConfig;
-    use crate::search_objs::search_state::{SearchState};
+    use crate::primitives::shared::move_to_alg;
+    use crate::search_objs::search_state::{Reporting, SearchState};
+    use std::sync::Arc;
     use std::thread;
 
 
@@ -255,4 +307,77 @@ mod tests {
         parse_setoption(&mut engine_config, "setoption name SoftNodes value notanumber");
         assert_eq!(engine_config.soft_nodes, None);
     }
+
+    #[test]
+    fn test_setoption_threads() {
+        let mut engine_config = EngineConfig::default();
+        assert_eq!(engine_config.threads, 1);
+
+        parse_setoption(&mut engine_config, "setoption name Threads value 4");
+        assert_eq!(engine_config.threads, 4);
+
+        // Out of range values are clamped to the advertised bounds.
+        parse_setoption(&mut engine_config, "setoption name Threads value 0");
+        assert_eq!(engine_config.threads, 1);
+
+        parse_setoption(&mut engine_config, "setoption name Threads value notanumber");
+        assert_eq!(engine_config.threads, 1);
+    }
+
+    #[test]
+    fn test_setoption_multi_pv() {
+        let mut engine_config = EngineConfig::default();
+        assert_eq!(engine_config.multi_pv, 1);
+
+        parse_setoption(&mut engine_config, "setoption name MultiPV value 3");
+        assert_eq!(engine_config.multi_pv, 3);
+
+        parse_setoption(&mut engine_config, "setoption name MultiPV value 0");
+        assert_eq!(engine_config.multi_pv, 1);
+    }
+
+    #[test]
+    fn test_go_searchmoves() {
+        let builder = thread::Builder::new().stack_size(80 * 1024 * 1024);
+        let handler = builder
+            .spawn(|| {
+                let mut search_state = SearchState::new(&EngineConfig::thin());
+                search_state.reporting = Reporting::Quiet;
+                let board_position = parse_position_command(&mut search_state, "position startpos");
+
+                // The move list ends at the first word that isn't a move
+                parse_go(&board_position, &mut search_state, "go searchmoves a2a3 h2h3 depth 5");
+                let search_moves: Vec<String> = search_state.search_moves.iter().map(move_to_alg).collect();
+                assert_eq!(search_moves, ["a2a3", "h2h3"]);
+                assert_eq!(search_state.stop_condition.depth, Some(5));
+
+                let best_move = move_to_alg(&search_state.pv_table.table[0][0]);
+                assert!(best_move == "a2a3" || best_move == "h2h3");
+
+                // A new go without searchmoves considers every move again
+                parse_go(&board_position, &mut search_state, "go depth 4");
+                assert!(search_state.search_moves.is_empty());
+            })
+            .unwrap();
+        handler.join().unwrap();
+    }
+
+    #[test]
+    fn test_setoption_eval_file() {
+        let mut engine_config = EngineConfig::default();
+        let embedded = engine_config.network.clone();
+
+        let net_path = concat!(env!("CARGO_MANIFEST_DIR"), "/nets/quantised-256.bin");
+        parse_setoption(&mut engine_config, &format!("setoption name EvalFile value {}", net_path));
+        assert!(!Arc::ptr_eq(&engine_config.network, &embedded));
+        let loaded = engine_config.network.clone();
+
+        // Wrong architecture - rejected, the loaded net stays
+        let wrong_net_path = concat!(env!("CARGO_MANIFEST_DIR"), "/nets/beans.bin");
+        parse_setoption(&mut engine_config, &format!("setoption name EvalFile value {}", wrong_net_path));
+        assert!(Arc::ptr_eq(&engine_config.network, &loaded));
+
+        parse_setoption(&mut engine_config, "setoption name EvalFile value <embedded>");
+        assert!(Arc::ptr_eq(&engine_config.network, &embedded));
+    }
 }
@@ -18,6 +18,7 @@ use movegen::attacks::KING_ATTACKS;
 use crate::bench::bench_engine;
 use crate::datagen::run_genfens;
 use crate::evaluation::evaluate::evaltest;
+use crate::evaluation::simd;
 use crate::gui::parse_position_command;
 use crate::gui::parse_setoption;
 use crate::gui::parse_ucinewgame;
@@ -25,6 +26,7 @@ use crate::gui::{parse_go};
 use crate::primitives::board::BoardPosition;
 use crate::search_objs::config::EngineConfig;
 use crate::search_objs::search_state::SearchState;
+use crate::search_objs::search_thread::SearchThread;
 
 /**********************************\
  ==================================
@@ -39,7 +41,10 @@ pub fn print_identification() {
     println!("id author Tomasz Stawowy");
     println!("option name Hash type spin default 256 min 0 max 1024");
     println!("option name SoftNodes type spin default 0 min 0 max 1000000000");
-    println!("option name Threads type spin default 1 min 1 max 1");
+    println!("option name Threads type spin default 1 min 1 max 256");
+    println!("option name Ponder type check default false");
+    println!("option name MultiPV type spin default 1 min 1 max 256");
+    println!("option name EvalFile type string default <embedded>");
     println!("uciok");
 }
 
@@ -48,6 +53,8 @@ pub fn uci_loop() {
     let mut engine_config: EngineConfig = EngineConfig::default();
     let mut search_state: SearchState = SearchState::new(&engine_config);
     let mut board_position: BoardPosition = parse_position_command(&mut search_state, "position startpos");
+    // Searches run on a worker thread, so stop/isready are answered while thinking
+    let mut search_thread = SearchThread::new(search_state);
     loop {  
         // Read user input
         let mut input = String::new();
@@ -63,23 +70,50 @@ pub fn uci_loop() {
 
         // Handle the command
         match words[0] {
-            "exit" => return,
-            "quit" => return,
-            "go" => parse_go(&board_position, &mut search_state, command),
-            "position" => {board_position = parse_position_command(&mut search_state, command)},
-            "eval" => evaltest(&board_position, &search_state),
-            "ucinewgame" => {board_position = parse_ucinewgame(&mut search_state)},
+            "exit" | "quit" => {
+                search_thread.stop();
+                return;
+            },
+            "go" => {
+                // The previous search reads the pondering flag until it's gone
+                search_thread.stop();
+                search_thread.set_pondering(words.contains(&"ponder"));
+                let board_position = board_position.clone();
+                let command = command.to_owned();
+                search_thread.spawn(move |search_state| parse_go(&board_position, search_state, &command));
+            },
+            "stop" => search_thread.stop(),
+            // The opponent played the expected move - the ponder search continues on our clock
+            "ponderhit" => search_thread.set_pondering(false),
+            "position" => {board_position = parse_position_command(search_thread.state(), command)},
+            "eval" => evaltest(&board_position, search_thread.state()),
+            "ttstats" => search_thread.state().print_tt_stats(),
+            "savehash" => {
+                let path = words[1..].join(" ");
+                match search_thread.state().save_tt(&path) {
+                    Ok(()) => println!("info string saved hash to {}", path),
+                    Err(err) => println!("info string savehash failed: {}", err),
+                }
+            },
+            "loadhash" => {
+                let path = words[1..].join(" ");
+                match search_thread.state().load_tt(&path) {
+                    Ok(()) => println!("info string loaded hash from {}", path),
+                    Err(err) => println!("info string loadhash failed: {}", err),
+                }
+            },
+            "ucinewgame" => {board_position = parse_ucinewgame(search_thread.state())},
             "uci" => print_identification(),
             "setoption" => {
                 parse_setoption(&mut engine_config, command);
-                search_state = SearchState::new(&engine_config);
-                board_position = parse_position_command(&mut search_state, "position startpos");
+                search_thread.replace(SearchState::new(&engine_config));
+                board_position = parse_position_command(search_thread.state(), "position startpos");
             },
             "genfens" => run_genfens(words),
             "printboard" => board_position.print_board(),
             "printbitboard" => print_bitboard(words[1].parse().unwrap_or_default()),
             "isready" => println!("readyok"),
-            "bench" => bench_engine(&mut search_state),
+            "bench" => bench_engine(search_thread.state()),
             //"see" => println!("See: {}", see_a_move(&board_position, parse_move(&board_position, words[1]).expect("Good Job. You've crashed the engine"))),
             // Add more commands here as needed
             _ => println!("Unknown command: {}", command),
@@ -92,6 +126,9 @@ fn main() {
     let builder = thread::Builder::new().stack_size(80 * 1024 * 1024);
     let handler = builder.spawn(|| {
 
+        // Pick the NNUE kernels before anything gets evaluated
+        simd::backend();
+
         let args: Vec<String> = env::args().collect();
 
         if args.get(1).map(|s| s.as_str()) == Some("bench") {
@@ -113,6 +150,11 @@ fn main() {
             return;
         }
 
+        if tokens.first().is_some_and(|&token| token == "perft") {
+            crate::movegen::perft::run_perft(tokens);
+            return;
+        }
+
         print_identification();
         uci_loop()
     }).unwrap();
@@ -21,9 +21,10 @@ lazy_static! {
         let mut pawn_attacks = [[0; 64]; 2];
         let once = Once::new();
         once.call_once(|| {
-            for square  in 0..64 {
-                pawn_attacks[0][square] = mask_pawn_attacks(0,square);
-                pawn_attacks[1][square] = mask_pawn_attacks(1,square);
+            for (side, side_attacks) in pawn_attacks.iter_mut().enumerate() {
+                for (square, attacks) in side_attacks.iter_mut().enumerate() {
+                    *attacks = mask_pawn_attacks(side, square);
+                }
             }
         });
             pawn_attacks
@@ -35,8 +36,8 @@ lazy_static! {
         let mut knight_attacks = [0; 64];
         let once = Once::new();
         once.call_once(|| {
-            for square  in 0..64 {
-                knight_attacks[square] = mask_knight_attacks(square);
+            for (square, attacks) in knight_attacks.iter_mut().enumerate() {
+                *attacks = mask_knight_attacks(square);
             }
         });
         knight_attacks
@@ -48,8 +49,8 @@ lazy_static! {
         let mut king_attacks = [0; 64];
         let once = Once::new();
         once.call_once(|| {
-            for square  in 0..64 {
-                king_attacks[square] = mask_king_attacks(square);
+            for (square, attacks) in king_attacks.iter_mut().enumerate() {
+                *attacks = mask_king_attacks(square);
             }
         });
         king_attacks
@@ -526,8 +527,8 @@ lazy_static! {
         let mut bishop_masks = [0; 64];
         let once = Once::new();
         once.call_once(|| {
-            for square  in 0..64 {
-                bishop_masks[square] = mask_bishop_attacks(square);
+            for (square, mask) in bishop_masks.iter_mut().enumerate() {
+                *mask = mask_bishop_attacks(square);
 
 
             }
@@ -573,8 +574,8 @@ lazy_static! {
         let mut rook_masks = [0; 64];
         let once = Once::new();
         once.call_once(|| {
-            for square  in 0..64 {
-                rook_masks[square] = mask_rook_attacks(square);
+            for (square, mask) in rook_masks.iter_mut().enumerate() {
+                *mask = mask_rook_attacks(square);
             }
         });
             rook_masks
@@ -707,7 +708,7 @@ pub fn get_least_valuable_attacker(board_position: &BoardPosition, square: u8) -
     
     let side = board_position.side;
 
-    for piece_idx in 0 + 6*side as usize..6 + 6*side as usize {
+    for piece_idx in 6*side as usize..6 + 6*side as usize {
         let attacker = Piece::new(piece_idx);
 
         let attacks = get_piece_attacks(board_position, square, attacker);
@@ -8,7 +8,7 @@ use crate::{
     get_bit, pop_bit, Piece, KING_ATTACKS, KNIGHT_ATTACKS, PAWN_ATTACKS,
 };
 use crate::movegen::attacks::{get_bishop_attacks, get_queen_attacks, get_rook_attacks};
-use crate::primitives::shared::{Move, MoveCode};
+use crate::primitives::shared::{KING_INDEX, Move, MoveCode};
 
 // ---------------------------------------------------------------------------
 // Constants
@@ -35,6 +35,10 @@ pub const CASTLING_RIGHTS: [u8; 64] = [
 pub trait MovegenType {
     const NOISY: bool;
     const QUIET: bool;
+    // Only moves that don't leave the king in check
+    const LEGAL: bool = false;
+    // Noisy and quiet moves out of check
+    const EVASION: bool = false;
 }
 
 pub struct QuietMovegen;
@@ -49,6 +53,29 @@ impl MovegenType for NoisyMovegen {
     const QUIET: bool = false;
 }
 
+pub struct LegalQuietMovegen;
+impl MovegenType for LegalQuietMovegen {
+    const NOISY: bool = false;
+    const QUIET: bool = true;
+    const LEGAL: bool = true;
+}
+
+pub struct LegalNoisyMovegen;
+impl MovegenType for LegalNoisyMovegen {
+    const NOISY: bool = true;
+    const QUIET: bool = false;
+    const LEGAL: bool = true;
+}
+
+/// All legal moves out of check, noisy and quiet together
+pub struct EvasionMovegen;
+impl MovegenType for EvasionMovegen {
+    const NOISY: bool = true;
+    const QUIET: bool = true;
+    const LEGAL: bool = true;
+    const EVASION: bool = true;
+}
+
 
 // ---------------------------------------------------------------------------
 // is_square_attacked
@@ -58,9 +85,13 @@ impl MovegenType for NoisyMovegen {
 /// **not** `board.side` (i.e. the side that is about to move is checking
 /// whether the given square is under attack by the opponent).
 pub fn is_square_attacked(square: u8, board: &BoardPosition) -> bool {
+    is_square_attacked_with(square, board, board.occupancies[2])
+}
+
+/// `is_square_attacked` with sliders seeing through everything not in `occ`
+fn is_square_attacked_with(square: u8, board: &BoardPosition, occ: u64) -> bool {
     // Check if the opponent of the side to move attacks this square.
     let opponent = board.side.invert();
-    let occ = board.occupancies[2];
 
     // Pawns: use PAWN_ATTACKS[board.side] which gives the reverse-attack
     // directions (i.e. squares from which an opponent pawn would attack).
@@ -126,6 +157,105 @@ pub fn is_square_attacked(square: u8, board: &BoardPosition) -> bool {
     false
 }
 
+// ---------------------------------------------------------------------------
+// Legality masks
+// ---------------------------------------------------------------------------
+
+/// Squares strictly between `a` and `b`, empty when they don't share a line
+pub fn between(a: usize, b: usize) -> u64 {
+    if get_rook_attacks(a, 0) & (1 << b) != 0 {
+        get_rook_attacks(a, 1 << b) & get_rook_attacks(b, 1 << a)
+    } else if get_bishop_attacks(a, 0) & (1 << b) != 0 {
+        get_bishop_attacks(a, 1 << b) & get_bishop_attacks(b, 1 << a)
+    } else {
+        0
+    }
+}
+
+/// The whole line through `a` and `b`, empty when they don't share one
+pub fn line_through(a: usize, b: usize) -> u64 {
+    if get_rook_attacks(a, 0) & (1 << b) != 0 {
+        get_rook_attacks(a, 0) & get_rook_attacks(b, 0) | (1 << a) | (1 << b)
+    } else if get_bishop_attacks(a, 0) & (1 << b) != 0 {
+        get_bishop_attacks(a, 0) & get_bishop_attacks(b, 0) | (1 << a) | (1 << b)
+    } else {
+        0
+    }
+}
+
+/// Enemy pieces giving check to the side to move
+pub fn checkers(board: &BoardPosition) -> u64 {
+    let side = board.side;
+    let them = 6 * side.invert() as usize;
+    let king_square = board.bitboards[KING_INDEX[side]].trailing_zeros() as usize;
+    let occ = board.occupancies[2];
+
+    let diagonal = board.bitboards[them + Piece::B as usize] | board.bitboards[them + Piece::Q as usize];
+    let straight = board.bitboards[them + Piece::R as usize] | board.bitboards[them + Piece::Q as usize];
+
+    (PAWN_ATTACKS[side][king_square] & board.bitboards[them + Piece::P as usize])
+        | (KNIGHT_ATTACKS[king_square] & board.bitboards[them + Piece::N as usize])
+        | (get_bishop_attacks(king_square, occ) & diagonal)
+        | (get_rook_attacks(king_square, occ) & straight)
+}
+
+/// Where the side to move's pieces may go without leaving the king in check. Pseudo-legal movegen uses `PSEUDO`,
+/// which allows everything.
+struct MoveMasks {
+    // Capturing the checker or blocking its ray, everything when not in check and nothing in double check
+    check_mask: u64,
+    checkers: u64,
+    // Pieces that may only move along the line through them and the king
+    pinned: u64,
+    king_square: usize,
+}
+
+impl MoveMasks {
+    const PSEUDO: MoveMasks = MoveMasks { check_mask: !0, checkers: 0, pinned: 0, king_square: 0 };
+
+    fn new(board: &BoardPosition) -> Self {
+        let side = board.side;
+        let them = 6 * side.invert() as usize;
+        let king_square = board.bitboards[KING_INDEX[side]].trailing_zeros() as usize;
+        let checkers = checkers(board);
+
+        let check_mask = match checkers.count_ones() {
+            0 => !0,
+            1 => between(king_square, checkers.trailing_zeros() as usize) | checkers,
+            _ => 0,
+        };
+
+        // Sliders that would see the king through our pieces
+        let their_occ = board.occupancies[side.invert()];
+        let diagonal = board.bitboards[them + Piece::B as usize] | board.bitboards[them + Piece::Q as usize];
+        let straight = board.bitboards[them + Piece::R as usize] | board.bitboards[them + Piece::Q as usize];
+        let mut snipers = (get_bishop_attacks(king_square, their_occ) & diagonal) | (get_rook_attacks(king_square, their_occ) & straight);
+
+        let mut pinned = 0;
+        while snipers != 0 {
+            let sniper = snipers.trailing_zeros() as usize;
+            pop_bit(&mut snipers, sniper);
+
+            let blockers = between(king_square, sniper) & board.occupancies[2];
+            if blockers.count_ones() == 1 && blockers & board.occupancies[side] != 0 {
+                pinned |= blockers;
+            }
+        }
+
+        Self { check_mask, checkers, pinned, king_square }
+    }
+
+    /// Targets a non-king piece on `source` may move to
+    #[inline(always)]
+    fn allowed(&self, source: usize) -> u64 {
+        if get_bit(self.pinned, source) {
+            self.check_mask & line_through(self.king_square, source)
+        } else {
+            self.check_mask
+        }
+    }
+}
+
 // ---------------------------------------------------------------------------
 // Move generation helpers
 // ---------------------------------------------------------------------------
@@ -147,6 +277,7 @@ fn push_move(moves: &mut ArrayVec<MoveEntry, 256>, source: u8, target: u8, move_
 fn generate_pawn_moves<Type: MovegenType>(
     board: &BoardPosition,
     side: Color,
+    masks: &MoveMasks,
     moves: &mut ArrayVec<MoveEntry, 256>,
 ) {
     let piece = if side == White { Piece::P } else { Piece::p };
@@ -162,21 +293,26 @@ fn generate_pawn_moves<Type: MovegenType>(
         pop_bit(&mut bb, source);
 
         let target = (source as isize + direction) as usize;
+        let allowed = masks.allowed(source);
 
         // Quiet moves (single push)
         if Type::QUIET && target < 64 && !get_bit(all_occ, target) {
             if source >= promo_rank_range.0 && source <= promo_rank_range.1 {
                 // Promotion
-                for promo in promotion_codes() {
-                    push_move(moves, source as u8, target as u8, promo);
+                if get_bit(allowed, target) {
+                    for promo in promotion_codes() {
+                        push_move(moves, source as u8, target as u8, promo);
+                    }
                 }
             } else {
-                push_move(moves, source as u8, target as u8, MoveCode::QuietMove);
+                if get_bit(allowed, target) {
+                    push_move(moves, source as u8, target as u8, MoveCode::QuietMove);
+                }
                     
                 // Double push
                 if source >= start_rank_range.0 && source <= start_rank_range.1 {
                     let target2 = (target as isize + direction) as usize;
-                    if target2 < 64 && !get_bit(all_occ, target2) {
+                    if target2 < 64 && !get_bit(all_occ, target2) && get_bit(allowed, target2) {
                         push_move(moves, source as u8, target2 as u8,MoveCode::DoublePush);
                     }
                 }
@@ -185,7 +321,7 @@ fn generate_pawn_moves<Type: MovegenType>(
 
         // Captures
         if Type::NOISY {
-            let mut attacks = PAWN_ATTACKS[side][source] & opp_occ;
+            let mut attacks = PAWN_ATTACKS[side][source] & opp_occ & allowed;
             while attacks != 0 {
                 let cap_target = attacks.trailing_zeros() as usize;
                 pop_bit(&mut attacks, cap_target);
@@ -204,7 +340,12 @@ fn generate_pawn_moves<Type: MovegenType>(
                 let ep_bit = PAWN_ATTACKS[side][source] & (1u64 << board.enpassant);
                 if ep_bit != 0 {
                     let ep_target = ep_bit.trailing_zeros() as u8;
-                    push_move(moves, source as u8, ep_target, MoveCode::EnPassant);
+                    let ep_move = Move::create(source as u8, ep_target, MoveCode::EnPassant);
+
+                    // Two pawns leave the rank at once, which masks can't describe. En passant is rare enough to just try it.
+                    if !Type::LEGAL || board.make_move(ep_move).is_some() {
+                        moves.push(MoveEntry { mv: ep_move, score: 0 });
+                    }
                 }
             }
         }
@@ -232,22 +373,30 @@ fn generate_king_moves<Type : MovegenType>(
         pop_bit(&mut bb, source);
 
         let mut attacks = KING_ATTACKS[source] & filter;
+        // Sliders checking the king also attack the squares behind it
+        let occ_without_king = board.occupancies[2] & !(1 << source);
 
         while attacks != 0 {
             let target = attacks.trailing_zeros() as usize;
             pop_bit(&mut attacks, target);
-            push_move(moves, source as u8, target as u8, move_code);
+
+            if !Type::LEGAL || !is_square_attacked_with(target as u8, board, occ_without_king) {
+                push_move(moves, source as u8, target as u8, move_code);
+            }
         }
     }
 }
 
 /// Generate castling moves for `side`.
-fn generate_castling_moves(
+fn generate_castling_moves<Type: MovegenType>(
     board: &BoardPosition,
     side: Color,
+    masks: &MoveMasks,
     moves: &mut ArrayVec<MoveEntry, 256>
 ) {
     let occ = board.occupancies[2];
+    // The king's own square is checked below, only the landing square is left to legal movegen
+    let lands_safely = |target: u8| !Type::LEGAL || (masks.checkers == 0 && !is_square_attacked(target, board));
 
     if side == White {
         // White kingside (O-O): king e1->g1, rook h1->f1
@@ -256,6 +405,7 @@ fn generate_castling_moves(
             && !get_bit(occ, 62)
             && !is_square_attacked(60, board)
             && !is_square_attacked(61, board)
+            && lands_safely(62)
         {
             push_move(moves, 60, 62, MoveCode::KingCastle);
         }
@@ -266,6 +416,7 @@ fn generate_castling_moves(
             && !get_bit(occ, 57)
             && !is_square_attacked(60, board)
             && !is_square_attacked(59, board)
+            && lands_safely(58)
         {
             push_move(moves, 60, 58, MoveCode::QueenCastle);
         }
@@ -276,6 +427,7 @@ fn generate_castling_moves(
             && !get_bit(occ, 6)
             && !is_square_attacked(4, board)
             && !is_square_attacked(5, board)
+            && lands_safely(6)
         {
             push_move(moves, 4, 6, MoveCode::KingCastle);
         }
@@ -286,6 +438,7 @@ fn generate_castling_moves(
             && !get_bit(occ, 1)
             && !is_square_attacked(4, board)
             && !is_square_attacked(3, board)
+            && lands_safely(2)
         {
             push_move(moves, 4, 2, MoveCode::QueenCastle);
         }
@@ -296,6 +449,7 @@ fn generate_castling_moves(
 fn generate_knight_moves<Type:MovegenType>(
     board: &BoardPosition,
     side: Color,
+    masks: &MoveMasks,
     moves: &mut ArrayVec<MoveEntry, 256>,
 ) {
     let piece = if side == White { Piece::N } else { Piece::n };
@@ -312,7 +466,7 @@ fn generate_knight_moves<Type:MovegenType>(
         let source: usize = bb.trailing_zeros() as usize;
         pop_bit(&mut bb, source);
 
-        let mut attacks = KNIGHT_ATTACKS[source] & filter;
+        let mut attacks = KNIGHT_ATTACKS[source] & filter & masks.allowed(source);
         while attacks != 0 {
             let target = attacks.trailing_zeros() as usize;
             pop_bit(&mut attacks, target);
@@ -325,6 +479,7 @@ fn generate_knight_moves<Type:MovegenType>(
 fn generate_bishop_moves<Type : MovegenType>(
     board: &BoardPosition,
     side: Color,
+    masks: &MoveMasks,
     moves: &mut ArrayVec<MoveEntry, 256>,
 ) {
     let piece = if side == White { Piece::B } else { Piece::b };
@@ -341,7 +496,7 @@ fn generate_bishop_moves<Type : MovegenType>(
         let source = bb.trailing_zeros() as usize;
         pop_bit(&mut bb, source);
 
-        let mut attacks = get_bishop_attacks(source, board.occupancies[2]) & filter;
+        let mut attacks = get_bishop_attacks(source, board.occupancies[2]) & filter & masks.allowed(source);
         while attacks != 0 {
             let target = attacks.trailing_zeros() as usize;
             pop_bit(&mut attacks, target);
@@ -354,6 +509,7 @@ fn generate_bishop_moves<Type : MovegenType>(
 fn generate_rook_moves<Type:MovegenType>(
     board: &BoardPosition,
     side: Color,
+    masks: &MoveMasks,
     moves: &mut ArrayVec<MoveEntry, 256>,
 ) {
     let piece = if side == White { Piece::R } else { Piece::r };
@@ -369,7 +525,7 @@ fn generate_rook_moves<Type:MovegenType>(
         let source = bb.trailing_zeros() as usize;
         pop_bit(&mut bb, source);
 
-        let mut attacks = get_rook_attacks(source, board.occupancies[2]) & filter;
+        let mut attacks = get_rook_attacks(source, board.occupancies[2]) & filter & masks.allowed(source);
         while attacks != 0 {
             let target = attacks.trailing_zeros() as usize;
             pop_bit(&mut attacks, target);
@@ -382,6 +538,7 @@ fn generate_rook_moves<Type:MovegenType>(
 fn generate_queen_moves<Type:MovegenType>(
     board: &BoardPosition,
     side: Color,
+    masks: &MoveMasks,
     moves: &mut ArrayVec<MoveEntry, 256>,
 ) {
     let piece = if side == White { Piece::Q } else { Piece::q };
@@ -397,7 +554,7 @@ fn generate_queen_moves<Type:MovegenType>(
         let source = bb.trailing_zeros() as usize;
         pop_bit(&mut bb, source);
 
-        let mut attacks = get_queen_attacks(source, board.occupancies[2]) & filter;
+        let mut attacks = get_queen_attacks(source, board.occupancies[2]) & filter & masks.allowed(source);
         while attacks != 0 {
             let target = attacks.trailing_zeros() as usize;
             pop_bit(&mut attacks, target);
@@ -436,15 +593,28 @@ pub fn generate_moves<Type: MovegenType>(board: &BoardPosition, list: &mut Array
     // Typical legal positions have ~35 moves; 64 avoids most reallocations.
 
 
-    generate_pawn_moves::<Type>(board, side, list);
+    let masks = if Type::LEGAL { MoveMasks::new(board) } else { MoveMasks::PSEUDO };
+
+    // The piece generators do one kind of move at a time, evasions share the masks between both
+    if Type::EVASION {
+        debug_assert!(masks.checkers != 0, "evasion movegen outside of check");
+        generate_with_masks::<LegalNoisyMovegen>(board, side, &masks, list);
+        generate_with_masks::<LegalQuietMovegen>(board, side, &masks, list);
+    } else {
+        generate_with_masks::<Type>(board, side, &masks, list);
+    }
+}
+
+fn generate_with_masks<Type: MovegenType>(board: &BoardPosition, side: Color, masks: &MoveMasks, list: &mut ArrayVec<MoveEntry, 256>) {
+    generate_pawn_moves::<Type>(board, side, masks, list);
     generate_king_moves::<Type>(board, side, list);
     if Type::QUIET {
-        generate_castling_moves(board, side, list);
+        generate_castling_moves::<Type>(board, side, masks, list);
     }
-    generate_knight_moves::<Type>(board, side, list);
-    generate_bishop_moves::<Type>(board, side, list);
-    generate_rook_moves::<Type>(board, side, list);
-    generate_queen_moves::<Type>(board, side, list);
+    generate_knight_moves::<Type>(board, side, masks, list);
+    generate_bishop_moves::<Type>(board, side, masks, list);
+    generate_rook_moves::<Type>(board, side, masks, list);
+    generate_queen_moves::<Type>(board, side, masks, list);
 }
 
 pub fn generate_all_moves(board: &BoardPosition) -> ArrayVec<MoveEntry, 256> {
@@ -456,6 +626,16 @@ pub fn generate_all_moves(board: &BoardPosition) -> ArrayVec<MoveEntry, 256> {
     moves
 }
 
+/// Like `generate_all_moves`, but every move can be played
+pub fn generate_legal_moves(board: &BoardPosition) -> ArrayVec<MoveEntry, 256> {
+    let mut moves = ArrayVec::new();
+
+    generate_moves::<LegalNoisyMovegen>(board, &mut moves);
+    generate_moves::<LegalQuietMovegen>(board, &mut moves);
+
+    moves
+}
+
 
 // ---------------------------------------------------------------------------
 // Tests
@@ -464,7 +644,9 @@ pub fn generate_all_moves(board: &BoardPosition) -> ArrayVec<MoveEntry, 256> {
 #[cfg(test)]
 mod tests {
     use crate::primitives::board::BoardPosition;
-    use crate::movegen::move_gen::is_square_attacked;
+    use arrayvec::ArrayVec;
+    use crate::movegen::move_gen::{EvasionMovegen, checkers, generate_all_moves, generate_legal_moves, generate_moves, is_square_attacked};
+    use crate::movepicker::MoveEntry;
     use crate::primitives::shared::{coordinates_to_squares, print_bitboard};
     use std::thread;
 
@@ -472,7 +654,7 @@ mod tests {
         let mut cnt = 0;
         for y in 0..8 {
             for x in 0..8 {
-                cnt = cnt * 2;
+                cnt *= 2;
                 if is_square_attacked(x + 8 * y, board_position) {
                     cnt += 1;
                 }
@@ -503,10 +685,7 @@ mod tests {
         let handler = builder
             .spawn(|| {
                 let board_pos = BoardPosition::new("8/8/8/8/8/4R3/8/8 b - - 0 1"); //Rook on e3
-                assert_eq!(
-                    is_square_attacked(coordinates_to_squares("d3"), &board_pos),
-                    true
-                );
+                assert!(is_square_attacked(coordinates_to_squares("d3"), &board_pos));
             })
             .unwrap();
         handler.join().unwrap();
@@ -518,10 +697,65 @@ mod tests {
         let handler = builder
             .spawn(|| {
                 let board_pos = BoardPosition::new("8/8/8/8/8/4R3/8/8 b - - 0 1"); //Rook on e3
-                assert_eq!(
-                    is_square_attacked(coordinates_to_squares("b1"), &board_pos),
-                    false
-                );
+                assert!(!is_square_attacked(coordinates_to_squares("b1"), &board_pos));
+            })
+            .unwrap();
+        handler.join().unwrap();
+    }
+
+    // Walks the tree and compares legal movegen with pseudo-legal movegen filtered by make_move,
+    // and with evasion movegen when in check
+    fn check_legal_moves(board_position: &BoardPosition, depth: usize) {
+        let mut legal: Vec<u16> = generate_legal_moves(board_position).iter().map(|entry| entry.mv.to_u16()).collect();
+        let mut filtered: Vec<u16> = generate_all_moves(board_position)
+            .iter()
+            .filter(|entry| board_position.make_move(entry.mv).is_some())
+            .map(|entry| entry.mv.to_u16())
+            .collect();
+        legal.sort();
+        filtered.sort();
+        assert_eq!(legal, filtered, "{}", board_position.to_fen(1));
+
+        if checkers(board_position) != 0 {
+            let mut evasions = ArrayVec::new();
+            generate_moves::<EvasionMovegen>(board_position, &mut evasions);
+            let mut evasions: Vec<u16> = evasions.iter().map(|entry: &MoveEntry| entry.mv.to_u16()).collect();
+            evasions.sort();
+            assert_eq!(evasions, legal, "{}", board_position.to_fen(1));
+        }
+
+        if depth == 0 {
+            return;
+        }
+
+        for entry in generate_legal_moves(board_position) {
+            check_legal_moves(&board_position.make_move(entry.mv).unwrap(), depth - 1);
+        }
+    }
+
+    #[test]
+    fn test_legal_movegen() {
+        let builder = thread::Builder::new().stack_size(80 * 1024 * 1024);
+        let handler = builder
+            .spawn(|| {
+                let fens = [
+                    "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+                    "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+                    "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+                    "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8",
+                    // En passant would expose the king along the rank
+                    "8/8/8/KPp4r/8/8/8/7k w - c6 0 1",
+                    // En passant takes the checking pawn, and one that doesn't stop a bishop check
+                    "8/8/8/2k5/3Pp3/8/8/4K3 b - d3 0 1",
+                    "5B2/8/8/8/1k1Pp3/8/8/4K3 b - d3 0 1",
+                    // Double check, and a pinned piece that can capture its pinner
+                    "4k3/8/8/8/8/5n2/4r3/4K3 w - - 0 1",
+                    "4k3/4r3/8/8/8/8/4R3/4K3 w - - 0 1",
+                ];
+
+                for fen in fens {
+                    check_legal_moves(&BoardPosition::new(fen), 2);
+                }
             })
             .unwrap();
         handler.join().unwrap();
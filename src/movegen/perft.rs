@@ -1,7 +1,13 @@
-use std::time::SystemTime;
-use crate::movegen::move_gen::{generate_all_moves};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Instant;
+
+use crate::movegen::move_gen::{generate_legal_moves};
 use crate::primitives::board::BoardPosition;
+use crate::primitives::shared::{KIWIPETE, Move, Piece, START_POSITION, move_to_alg};
 
+// Plain recursive perft, the reference the faster modes are tested against
+#[cfg(test)]
 pub fn perft_driver(board_position: &BoardPosition, depth: usize) -> usize {
 
     if depth == 0 {
@@ -9,18 +15,12 @@ pub fn perft_driver(board_position: &BoardPosition, depth: usize) -> usize {
     }
 
     //print_board(&search_state.board_position);
-    let movelist = generate_all_moves(board_position);
+    let movelist = generate_legal_moves(board_position);
     
     let mut movecount = 0;
     
     for i in movelist {
-
-        let result = board_position.make_move(i.mv);
-        if result.is_none() {
-            continue;
-        }
-
-        let new_board = result.unwrap();
+        let new_board = board_position.make_move(i.mv).expect("legal movegen gave an illegal move");
         movecount += perft_driver(&new_board, depth - 1);
     }
 
@@ -28,8 +28,108 @@ pub fn perft_driver(board_position: &BoardPosition, depth: usize) -> usize {
 
 }
 
+/// Node counts of subtrees, keyed by `hash` and depth. Shared by all perft threads without locks:
+/// the key is stored xored with the data, so an entry torn by two writers doesn't match anything.
+pub struct PerftTable {
+    entries: Vec<PerftEntry>,
+}
+
+#[derive(Default)]
+struct PerftEntry {
+    key: AtomicU64,
+    // Node count << 8 | depth
+    data: AtomicU64,
+}
+
+impl PerftTable {
+    pub fn new(megabytes: usize) -> Self {
+        let count = (megabytes * 1024 * 1024 / size_of::<PerftEntry>()).max(1);
+        Self { entries: (0..count).map(|_| PerftEntry::default()).collect() }
+    }
+
+    #[inline(always)]
+    fn entry(&self, hash: u64) -> &PerftEntry {
+        &self.entries[((hash as u128 * self.entries.len() as u128) >> 64) as usize]
+    }
+
+    fn probe(&self, hash: u64, depth: usize) -> Option<u64> {
+        let entry = self.entry(hash);
+        let data = entry.data.load(Ordering::Relaxed);
+
+        (entry.key.load(Ordering::Relaxed) ^ data == hash && data & 0xff == depth as u64).then_some(data >> 8)
+    }
+
+    fn store(&self, hash: u64, depth: usize, nodes: u64) {
+        let entry = self.entry(hash);
+        let data = nodes << 8 | depth as u64;
+
+        entry.key.store(hash ^ data, Ordering::Relaxed);
+        entry.data.store(data, Ordering::Relaxed);
+    }
+}
+
+/// Perft with bulk counting at depth 1 and, given a table, hashed subtrees
+pub fn perft_hashed(board_position: &BoardPosition, depth: usize, table: Option<&PerftTable>) -> u64 {
+    if depth == 0 {
+        return 1;
+    }
+
+    let movelist = generate_legal_moves(board_position);
+
+    // Every generated move is legal, so the leaves don't have to be made
+    if depth == 1 {
+        return movelist.len() as u64;
+    }
+
+    if let Some(nodes) = table.and_then(|table| table.probe(board_position.hash, depth)) {
+        return nodes;
+    }
+
+    let mut movecount = 0;
+    for i in movelist {
+        let new_board = board_position.make_move(i.mv).expect("legal movegen gave an illegal move");
+        movecount += perft_hashed(&new_board, depth - 1, table);
+    }
+
+    if let Some(table) = table {
+        table.store(board_position.hash, depth, movecount);
+    }
+
+    movecount
+}
+
+/// Node count below every root move. The root moves are split between `threads` threads,
+/// which share the table.
+pub fn perft_divide(board_position: &BoardPosition, depth: usize, threads: usize, table: Option<&PerftTable>) -> Vec<(Move, u64)> {
+    if depth == 0 {
+        return Vec::new();
+    }
+
+    let root_moves: Vec<Move> = generate_legal_moves(board_position).iter().map(|entry| entry.mv).collect();
+    let counts: Vec<AtomicU64> = root_moves.iter().map(|_| AtomicU64::new(0)).collect();
+    let next_move = AtomicUsize::new(0);
+
+    thread::scope(|scope| {
+        for _ in 0..threads.clamp(1, root_moves.len().max(1)) {
+            thread::Builder::new().stack_size(16 * 1024 * 1024).spawn_scoped(scope, || {
+                loop {
+                    let index = next_move.fetch_add(1, Ordering::Relaxed);
+                    let Some(&mv) = root_moves.get(index) else {
+                        break;
+                    };
+
+                    let new_board = board_position.make_move(mv).expect("legal movegen gave an illegal move");
+                    counts[index].store(perft_hashed(&new_board, depth - 1, table), Ordering::Relaxed);
+                }
+            }).expect("failed to spawn a perft thread");
+        }
+    });
+
+    root_moves.into_iter().zip(counts).map(|(mv, count)| (mv, count.into_inner())).collect()
+}
 
-pub fn perft(board_position: &BoardPosition, depth: usize) {
+/// `go perft` - prints the node count below every root move, then the total
+pub fn perft(board_position: &BoardPosition, depth: usize, threads: usize, hash: usize) {
     
     board_position.print_board();
     
@@ -37,44 +137,91 @@ pub fn perft(board_position: &BoardPosition, depth: usize) {
         return;
     }
 
-    let now = SystemTime::now();
-    let movelist = generate_all_moves(board_position);
+    let now = Instant::now();
+    let table = (hash > 0).then(|| PerftTable::new(hash));
+    let divide = perft_divide(board_position, depth, threads, table.as_ref());
 
-    let mut movecount = 0;
+    for (mv, count) in &divide {
+        println!("{}: {}", move_to_alg(mv), count);
+    }
 
-    for i in movelist {
-        let result = board_position.make_move(i.mv);
-        if result.is_none() {
-            continue;
+    let movecount: u64 = divide.iter().map(|(_, count)| count).sum();
+    let elapsed = now.elapsed();
+
+    println!();
+    println!("Perft Time {} ms", elapsed.as_millis());
+    println!("Moves: {}", movecount);
+    println!("NPS: {}", (movecount as f64 / elapsed.as_secs_f64().max(0.001)) as u64);
+}
+
+/// `Dual perft <depth> [threads <n>] [hash <MB>] [startpos | kiwipete | fen <FEN>]`
+pub fn run_perft(tokens: Vec<&str>) {
+    let mut depth: Option<usize> = None;
+    let mut threads = 1;
+    let mut hash = 64;
+    let mut fen = START_POSITION.to_owned();
+
+    let mut i = 1;
+    while i < tokens.len() {
+        match tokens[i] {
+            "threads" => {
+                threads = tokens.get(i + 1).and_then(|value| value.parse().ok()).unwrap_or(threads);
+                i += 2;
+            }
+            "hash" => {
+                hash = tokens.get(i + 1).and_then(|value| value.parse().ok()).unwrap_or(hash);
+                i += 2;
+            }
+            "startpos" => {
+                fen = START_POSITION.to_owned();
+                i += 1;
+            }
+            "kiwipete" => {
+                fen = KIWIPETE.to_owned();
+                i += 1;
+            }
+            // The FEN takes the rest of the command
+            "fen" => {
+                fen = tokens[i + 1..].join(" ");
+                i = tokens.len();
+            }
+            other => {
+                depth = depth.or(other.parse().ok());
+                i += 1;
+            }
         }
+    }
 
-        let new_board = result.unwrap();
+    let Some(depth) = depth else {
+        eprintln!("perft: invalid or missing depth");
+        return;
+    };
 
-        let cnt= perft_driver(&new_board, depth - 1);
-        println!("{:?}, Moves: {}", i.mv, cnt);
+    match perft_position(&fen) {
+        Ok(board_position) => perft(&board_position, depth, threads, hash),
+        Err(err) => eprintln!("perft: {}", err),
+    }
+}
 
-        movecount += cnt;
+// Movegen assumes a king of each color, so anything else is refused up front
+fn perft_position(fen: &str) -> Result<BoardPosition, String> {
+    if fen.trim().is_empty() {
+        return Err("missing FEN".to_string());
     }
 
-    match now.elapsed() {
-        Ok(elapsed) => {
-            println!("Perft Time {} ms", elapsed.as_millis());
-        }
-        Err(e) => {
-            // an error occurred!
-            println!("Error: {e:?}");
-        }
+    let board_position = BoardPosition::new(fen);
+    if board_position.bitboards[Piece::K as usize].count_ones() != 1 || board_position.bitboards[Piece::k as usize].count_ones() != 1 {
+        return Err("the position needs exactly one king per side".to_string());
     }
 
-    println!("Moves: {}", movecount);
-    
+    Ok(board_position)
 }
 
 #[cfg(test)]
 mod tests{
     use std::thread;
     use crate::movegen::move_gen::{generate_all_moves};
-    use crate::movegen::perft::perft_driver;
+    use crate::movegen::perft::{PerftTable, perft_divide, perft_driver, perft_hashed, perft_position};
     use crate::primitives::board::BoardPosition;
     use crate::primitives::shared::{ENDGAME_PERFT, KIWIPETE, START_POSITION};
 
@@ -121,6 +268,46 @@ mod tests{
         handler.join().unwrap();
     }
 
+    #[test]
+    fn test_hashed_parallel_perft() {
+        let builder = thread::Builder::new().stack_size(80 * 1024 * 1024);
+        let handler = builder
+            .spawn(|| {
+                let table = PerftTable::new(16);
+
+                // Bulk counting alone, then with the table shared by several threads
+                assert_eq!(perft_hashed(&BoardPosition::new(ENDGAME_PERFT), 6, None), 11030083);
+
+                let divide = perft_divide(&BoardPosition::new(KIWIPETE), 5, 4, Some(&table));
+                assert_eq!(divide.len(), 48);
+                assert_eq!(divide.iter().map(|(_, count)| count).sum::<u64>(), 193690690);
+
+                // Every root move keeps its own count, whichever thread searched it
+                let board_position = BoardPosition::new(START_POSITION);
+                for (mv, count) in perft_divide(&board_position, 4, 3, Some(&table)) {
+                    let new_board = board_position.make_move(mv).unwrap();
+                    assert_eq!(count as usize, perft_driver(&new_board, 3), "Divide mismatch for {:?}", mv);
+                }
+            })
+            .unwrap();
+        handler.join().unwrap();
+    }
+
+    #[test]
+    fn test_perft_position_validation() {
+        let builder = thread::Builder::new().stack_size(80 * 1024 * 1024);
+        let handler = builder
+            .spawn(|| {
+                assert!(perft_position(KIWIPETE).is_ok());
+                assert!(perft_position("").is_err());
+                assert!(perft_position("8/8/8/8/8/8/8/8 w - - 0 1").is_err());
+                assert!(perft_position("4k3/8/8/8/8/8/8/KK6 w - - 0 1").is_err());
+                assert!(perft_position("4K3/8/8/8/8/8/8/4K3 w - - 0 1").is_err());
+            })
+            .unwrap();
+        handler.join().unwrap();
+    }
+
     pub fn test_perft_driver_occupancies(board_position: &BoardPosition, depth: usize) -> usize {
 
         if depth == 0 {
@@ -1,8 +1,8 @@
 use arrayvec::ArrayVec;
 
-use crate::movegen::move_gen::{NoisyMovegen, QuietMovegen, generate_moves};
+use crate::movegen::move_gen::{EvasionMovegen, NoisyMovegen, QuietMovegen, generate_moves};
 use crate::primitives::board::BoardPosition;
-use crate::primitives::consts::{FIRST_KILLER_BONUS};
+use crate::primitives::consts::{CAPTURE_HISTORY_SCALE};
 use crate::primitives::shared::Move;
 use crate::search_objs::search_state::SearchState;
 use crate::search_objs::see::{see_a_move_threshold};
@@ -11,8 +11,14 @@ use crate::search_objs::see::{see_a_move_threshold};
 pub enum Stage {
     HashMove,
     Movegen,
+    Evasions,
     Noisy,
+    Killer1,
+    Killer2,
+    Countermove,
+    GenerateQuiet,
     Quiet,
+    QuietChecks,
     //GenerateNoisy,
     //GoodNoisy,
     //Quiet,
@@ -28,10 +34,19 @@ pub struct MoveEntry {
 pub struct MovePicker {
     list: ArrayVec<MoveEntry, 256>,
     tt_move: Move,
+    // Quiet moves tried before quiet movegen, null when they weren't played
+    killers: [Move; 2],
+    countermove: Move,
     stage: Stage,
     bad_noisy: ArrayVec<Move, 16>,
     bad_noisy_idx: usize,
-    skip_quiets: bool
+    skip_quiets: bool,
+    // In check - every move comes from the evasion generator
+    evasion: bool,
+    // Quiescence also tries quiet moves that give check
+    quiet_checks: bool,
+    // Root pickers only return moves allowed by `go searchmoves` and MultiPV
+    root: bool,
     //noisy_count: usize,
 }
 
@@ -41,21 +56,42 @@ impl MovePicker {
         Self {
             list: ArrayVec::new_const(),
             tt_move,
+            killers: [Move::create_null(); 2],
+            countermove: Move::create_null(),
             stage:  Stage::HashMove,
             bad_noisy: ArrayVec::new_const(),
             bad_noisy_idx: 0,
-            skip_quiets: false
+            skip_quiets: false,
+            evasion: false,
+            quiet_checks: false,
+            root: false,
         }
     }
 
+    pub const fn new_root(tt_move: Move) -> Self {
+        let mut move_picker = Self::new(tt_move);
+        move_picker.root = true;
+        move_picker
+    }
+
     //pub fn next<NODE: NodeType>(&mut self, board_position: &BoardPosition, search_state: &SearchState, quiescence: bool) -> Option<(Move, BoardPosition)> {
     pub fn next(&mut self, board_position: &BoardPosition, search_state: &SearchState, quiescence: bool) -> Option<(Move, BoardPosition)> {
+        loop {
+            let (mv, new_board) = self.next_move(board_position, search_state, quiescence)?;
+
+            if !self.root || search_state.is_root_move_allowed(mv) {
+                return Some((mv, new_board));
+            }
+        }
+    }
+
+    fn next_move(&mut self, board_position: &BoardPosition, search_state: &SearchState, quiescence: bool) -> Option<(Move, BoardPosition)> {
         
         if self.stage == Stage::HashMove {
 
             self.stage = Stage::Movegen;
 
-            if !self.tt_move.is_null() && board_position.can_make_move(self.tt_move){
+            if !self.tt_move.is_null() && board_position.is_pseudo_legal(self.tt_move){
 
                 let new_board= board_position.make_move(self.tt_move);
                 
@@ -63,13 +99,39 @@ impl MovePicker {
                     return Some((self.tt_move, new_board));
                 }
             }
+
+            // Not played, so the lists don't have to skip it
+            self.tt_move = Move::create_null();
         }
 
         if self.stage == Stage::Movegen {
             //TODO: switch
-            generate_moves::<NoisyMovegen>(board_position, &mut self.list);
-            self.score_moves(board_position, search_state);
-            self.stage = Stage::Noisy;
+            if self.evasion {
+                generate_moves::<EvasionMovegen>(board_position, &mut self.list);
+                self.score_moves(board_position, search_state);
+                self.stage = Stage::Evasions;
+            } else {
+                generate_moves::<NoisyMovegen>(board_position, &mut self.list);
+                self.score_moves(board_position, search_state);
+                self.stage = Stage::Noisy;
+            }
+        }
+
+        if self.stage == Stage::Evasions {
+            while !self.list.is_empty() {
+                let entry = self.get_best_entry();
+
+                if entry.mv == self.tt_move || (self.skip_quiets && entry.mv.is_quiet()) {
+                    continue;
+                }
+
+                // Evasions are legal already
+                if let Some(new_board) = board_position.make_move(entry.mv) {
+                    return Some((entry.mv, new_board));
+                }
+            }
+
+            return None;
         }
         
         if self.stage == Stage::Noisy {
@@ -77,6 +139,10 @@ impl MovePicker {
             while !self.list.is_empty() {
                 let entry = self.get_best_entry();
 
+                if entry.mv == self.tt_move {
+                    continue;
+                }
+
                 // if NODE::ROOT {
                 //     self.score_noisy(td);
                 // }
@@ -86,7 +152,14 @@ impl MovePicker {
                 let new_board= board_position.make_move(entry.mv);
                     
                 if let Some(new_board) = new_board {
-                    if !see_a_move_threshold(board_position, entry.mv, &new_board, 0) {
+                    // Captures that keep working out may lose a bit of material
+                    let threshold = if entry.mv.is_capture() {
+                        -(search_state.get_capture_history(board_position, entry.mv) as i32) / 32
+                    } else {
+                        0
+                    };
+
+                    if !see_a_move_threshold(board_position, entry.mv, &new_board, threshold) {
                         self.bad_noisy.push(entry.mv);
                         continue;
                     }
@@ -95,12 +168,60 @@ impl MovePicker {
                 }
             }
 
-            if quiescence {
+            if quiescence && self.quiet_checks {
+                generate_moves::<QuietMovegen>(board_position, &mut self.list);
+                self.list.retain(|entry| board_position.gives_check(entry.mv));
+                self.score_moves(board_position, search_state);
+                self.stage = Stage::QuietChecks;
+            } else if quiescence {
                 return None;
                 // Currently no need to check bad noisy in quiescence (they are always pruned)
                 //self.stage = Stage::BadNoisy;
             } else if self.skip_quiets {
                 self.stage = Stage::BadNoisy;
+            } else {
+                self.stage = Stage::Killer1;
+            }
+        }
+
+        // Killers and the countermove are quiets too
+        if self.skip_quiets && self.stage >= Stage::Killer1 && self.stage <= Stage::Countermove {
+            self.stage = Stage::BadNoisy;
+        }
+
+        if self.stage == Stage::Killer1 {
+            self.stage = Stage::Killer2;
+
+            let killer = search_state.get_killer_moves()[0];
+            if let Some(new_board) = self.try_quiet(board_position, killer) {
+                self.killers[0] = killer;
+                return Some((killer, new_board));
+            }
+        }
+
+        if self.stage == Stage::Killer2 {
+            self.stage = Stage::Countermove;
+
+            let killer = search_state.get_killer_moves()[1];
+            if let Some(new_board) = self.try_quiet(board_position, killer) {
+                self.killers[1] = killer;
+                return Some((killer, new_board));
+            }
+        }
+
+        if self.stage == Stage::Countermove {
+            self.stage = Stage::GenerateQuiet;
+
+            let countermove = search_state.get_countermove();
+            if let Some(new_board) = self.try_quiet(board_position, countermove) {
+                self.countermove = countermove;
+                return Some((countermove, new_board));
+            }
+        }
+
+        if self.stage == Stage::GenerateQuiet {
+            if self.skip_quiets {
+                self.stage = Stage::BadNoisy;
             } else {
                 generate_moves::<QuietMovegen>(board_position, &mut self.list);
                 self.score_moves(board_position, search_state);
@@ -117,6 +238,10 @@ impl MovePicker {
             while !self.list.is_empty() {
                 let entry = self.get_best_entry();
 
+                if self.already_tried(entry.mv) {
+                    continue;
+                }
+
                 let new_board= board_position.make_move(entry.mv);
                     
                 if let Some(new_board) = new_board {
@@ -127,6 +252,22 @@ impl MovePicker {
             self.stage = Stage::BadNoisy;
         }
 
+        if self.stage == Stage::QuietChecks {
+            while !self.list.is_empty() {
+                let entry = self.get_best_entry();
+
+                if entry.mv == self.tt_move {
+                    continue;
+                }
+
+                if let Some(new_board) = board_position.make_move(entry.mv) {
+                    return Some((entry.mv, new_board));
+                }
+            }
+
+            return None;
+        }
+
         if self.stage == Stage::BadNoisy {
             while self.bad_noisy_idx < self.bad_noisy.len() {
 
@@ -144,10 +285,33 @@ impl MovePicker {
         None
     }
 
+    fn already_tried(&self, mv: Move) -> bool {
+        mv == self.tt_move || mv == self.killers[0] || mv == self.killers[1] || mv == self.countermove
+    }
+
+    // Killers and countermoves, played only if they fit this position and weren't tried yet
+    fn try_quiet(&self, board_position: &BoardPosition, mv: Move) -> Option<BoardPosition> {
+        if mv.is_null() || self.already_tried(mv) || !board_position.is_pseudo_legal(mv) {
+            return None;
+        }
+
+        board_position.make_move(mv)
+    }
+
     pub fn skip_quiets(&mut self) {
         self.skip_quiets = true;
     }
 
+    /// The side to move is in check, so only evasions are generated
+    pub fn evasions(&mut self) {
+        self.evasion = true;
+    }
+
+    /// Quiescence pickers also return quiet checks after the noisy moves
+    pub fn quiet_checks(&mut self) {
+        self.quiet_checks = true;
+    }
+
     fn get_best_entry(&mut self) -> MoveEntry {
         let mut best_index = 0;
         let mut best_score = i32::MIN;
@@ -172,19 +336,59 @@ impl MovePicker {
         if mv.is_capture() {
             let victim = board_position.get_victim(mv);
             let mvv = SearchState::get_mvv_lva(victim, board_position.get_piece(mv));
-            
-            return mvv;
-            //return mvv + 
-            //    self.capt_history_moves[self.board_position.mailbox[mv.get_target_square() as usize] as usize][self.get_piece(mv) as usize][mv.get_target_square() as usize];
-        }
 
-        if search_state.ply < 256 {
-            if search_state.killer_moves[search_state.ply] == mv {
-                return FIRST_KILLER_BONUS;
-            }
+            // Can reorder attackers of the same victim type, never victim types
+            return mvv + search_state.get_capture_history(board_position, mv) as i32 * CAPTURE_HISTORY_SCALE;
         }
 
         // History heuristic
-        search_state.get_quiet_history(board_position.side, mv) as i32
+        search_state.get_quiet_score(board_position, mv)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use crate::movegen::move_gen::generate_all_moves;
+    use crate::movepicker::MovePicker;
+    use crate::primitives::board::BoardPosition;
+    use crate::primitives::shared::{Move, MoveCode};
+    use crate::search_objs::config::EngineConfig;
+    use crate::search_objs::search_state::SearchState;
+
+    #[test]
+    fn test_killer_stages() {
+        let builder = thread::Builder::new().stack_size(80 * 1024 * 1024);
+        let handler = builder
+            .spawn(|| {
+                let mut search_state = SearchState::new(&EngineConfig::thin());
+                let board_position = BoardPosition::new("r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1");
+                let legal_moves = generate_all_moves(&board_position)
+                    .iter()
+                    .filter(|entry| board_position.make_move(entry.mv).is_some())
+                    .count();
+
+                // Ne5-d3 and Qf3-f5 fit, Qf3-f8 doesn't, and the TT move is a killer as well
+                let tt_move = Move::create(28, 43, MoveCode::QuietMove);
+                search_state.update_killer_move(Move::create(45, 5, MoveCode::QuietMove));
+                search_state.update_killer_move(tt_move);
+                search_state.killer_moves[0][1] = Move::create(45, 29, MoveCode::QuietMove);
+                assert!(board_position.is_pseudo_legal(search_state.killer_moves[0][1]));
+                assert!(!board_position.is_pseudo_legal(Move::create(45, 5, MoveCode::QuietMove)));
+
+                let mut move_picker = MovePicker::new(tt_move);
+                let mut picked: Vec<Move> = Vec::new();
+                while let Some((mv, _)) = move_picker.next(&board_position, &search_state, false) {
+                    assert!(!picked.contains(&mv));
+                    picked.push(mv);
+                }
+
+                assert_eq!(picked.len(), legal_moves);
+                assert!(picked[0] == tt_move);
+                // Winning captures, then the remaining killer
+                let killer_index = picked.iter().position(|&mv| mv == Move::create(45, 29, MoveCode::QuietMove)).unwrap();
+                assert!(picked[..killer_index].iter().skip(1).all(|mv| mv.is_capture()));
+            })
+            .unwrap();
+        handler.join().unwrap();
+    }
+}
@@ -1,7 +1,7 @@
-use crate::movegen::attacks::get_piece_attacks;
+use crate::movegen::attacks::{KNIGHT_ATTACKS, PAWN_ATTACKS, get_bishop_attacks, get_piece_attacks, get_queen_attacks, get_rook_attacks};
 use crate::movegen::move_gen::{CASTLING_RIGHTS, is_square_attacked};
 use crate::primitives::shared::Color::{Black, White};
-use crate::primitives::shared::{ASCII_PIECES, Castle, Color, KING_INDEX, Move, Piece, SQUARE_TO_COORDINATES, get_bit, pop_bit, set_bit};
+use crate::primitives::shared::{ASCII_PIECES, Castle, Color, KING_INDEX, Move, MoveCode, Piece, SQUARE_TO_COORDINATES, get_bit, pop_bit, set_bit};
 use crate::primitives::hash::{compute_hash, get_zobrist_keys};
 
 #[allow(non_camel_case_types)]
@@ -23,6 +23,12 @@ pub struct BoardPosition {
     pub castle: usize,
 
     pub hash: u64,
+    // Pawns only, for correction history
+    pub pawn_hash: u64,
+    // Everything but pawns, per color
+    pub non_pawn_hash: [u64; 2],
+    // Piece counts only, squares don't matter
+    pub material_hash: u64,
 
     pub fifty_mr: u8,
 }
@@ -45,11 +51,18 @@ impl BoardPosition {
             enpassant: 0,
             castle: 0,
             hash: 0,
+            pawn_hash: 0,
+            non_pawn_hash: [0; 2],
+            material_hash: 0,
             fifty_mr: 0
         };
 
         board_position.parse_fen(fen);
-        board_position.hash = compute_hash(&board_position);
+        let keys = compute_hash(&board_position);
+        board_position.hash = keys.hash;
+        board_position.pawn_hash = keys.pawn_hash;
+        board_position.non_pawn_hash = keys.non_pawn_hash;
+        board_position.material_hash = keys.material_hash;
 
         board_position
     }
@@ -67,7 +80,7 @@ impl BoardPosition {
         let mut rank = 0;
         let mut file = 0;
 
-        while let Some(ch) = fen_chars.next() {
+        for ch in fen_chars.by_ref() {
             if ch.is_ascii_alphabetic() {
                 let piece = match ch {
                     'P' => Piece::P,
@@ -87,7 +100,7 @@ impl BoardPosition {
                 let square = rank * 8 + file;
                     self.add_piece(square, piece, false);
                     file += 1;
-            } else if ch.is_digit(10) {
+            } else if ch.is_ascii_digit() {
                 let offset = ch.to_digit(10).unwrap() as usize;
                 file += offset;
             } else if ch == '/' {
@@ -107,7 +120,7 @@ impl BoardPosition {
             fen_chars.next();
         }
 
-        while let Some(ch) = fen_chars.next() {
+        for ch in fen_chars.by_ref() {
             if ch == ' ' {
                 break;
             }
@@ -150,7 +163,7 @@ impl BoardPosition {
 
         let mut word = String::new();
 
-        while let Some(c) = fen_chars.next() {
+        for c in fen_chars.by_ref() {
             if c.is_whitespace() {
                 break;
             }
@@ -190,6 +203,7 @@ impl BoardPosition {
     pub fn make_null_move(&self) -> BoardPosition {
         let mut new_board=  self.clone();
         
+        // No piece moves, so only `hash` changes
         new_board.side = self.side.invert();
         new_board.hash ^= get_zobrist_keys().side_key;
 
@@ -210,19 +224,19 @@ impl BoardPosition {
         debug_assert!(self.mailbox[square] == piece);
         debug_assert!(get_bit(self.bitboards[piece as usize], square));
 
-        if update_hash {
-            self.hash ^= get_zobrist_keys().piece_keys[piece as usize][square];
-        }
-
         self.mailbox[square] = Piece::NONE;
         pop_bit(&mut self.occupancies[piece.get_side()], square);
         pop_bit(&mut self.bitboards[piece as usize], square);
+
+        if update_hash {
+            self.update_keys(square, piece);
+        }
     }
 
     #[inline(always)]
     pub fn add_piece(&mut self, square: usize, piece: Piece, update_hash: bool) {
         if update_hash {
-            self.hash ^= get_zobrist_keys().piece_keys[piece as usize][square];
+            self.update_keys(square, piece);
         }
 
         self.mailbox[square] = piece;
@@ -230,6 +244,22 @@ impl BoardPosition {
         set_bit(&mut self.bitboards[piece as usize], square);
     }
 
+    // Toggles `piece` on `square` in every key. Called with the piece count not including this piece,
+    // which is before adding it or after removing it.
+    #[inline(always)]
+    fn update_keys(&mut self, square: usize, piece: Piece) {
+        let keys = get_zobrist_keys();
+        let key = keys.piece_keys[piece as usize][square];
+
+        self.hash ^= key;
+        if piece == Piece::P || piece == Piece::p {
+            self.pawn_hash ^= key;
+        } else {
+            self.non_pawn_hash[piece.get_side()] ^= key;
+        }
+        self.material_hash ^= keys.piece_keys[piece as usize][self.bitboards[piece as usize].count_ones() as usize];
+    }
+
     #[inline(always)]
     pub fn get_victim(&self, mv: Move) -> Piece {
         if mv.is_enpassant() {
@@ -381,31 +411,140 @@ impl BoardPosition {
     //     true
     // }
 
-    // 99.9% correct
-    pub fn can_make_move(&self, mv: Move) -> bool {
-        let piece = self.mailbox[mv.get_source_square() as usize];
-        let source = mv.get_source_square();
-        let target = mv.get_target_square();
+    /// Whether `mv` is one of the moves `generate_all_moves` would give here, i.e. playable if it doesn't leave
+    /// the king in check. Moves from the TT, killers and countermoves may come from other positions
+    /// or hash collisions, so they have to pass this before `make_move`.
+    pub fn is_pseudo_legal(&self, mv: Move) -> bool {
+        // Codes 6 and 7 aren't moves
+        let code = (mv.to_u16() >> 12) & 0xf;
+        if code == 6 || code == 7 {
+            return false;
+        }
+
+        let source = mv.get_source_square() as usize;
+        let target = mv.get_target_square() as usize;
+        let piece = self.mailbox[source];
+        let victim = self.mailbox[target];
 
         if piece == Piece::NONE || piece.get_side() != self.side {
             return false;
         }
 
-        if (piece == Piece::P || piece == Piece::p) && !mv.is_capture() {
-            return source % 8 == target % 8;
-        }
+        let is_pawn = piece == Piece::P || piece == Piece::p;
+        // Pawns move towards lower squares for white
+        let forward = |square: usize, steps: usize| if self.side == White { square.checked_sub(8 * steps) } else { Some(square + 8 * steps).filter(|&sq| sq < 64) };
 
-        if mv.get_castling() {
-            return piece == Piece::K || piece == Piece::k;
+        match mv.get_move_code() {
+            MoveCode::KingCastle | MoveCode::QueenCastle => return self.is_castling_pseudo_legal(mv),
+            MoveCode::EnPassant => {
+                return is_pawn
+                    && self.enpassant != 0
+                    && target == self.enpassant as usize
+                    && PAWN_ATTACKS[self.side][source] & (1 << target) != 0;
+            }
+            MoveCode::DoublePush => {
+                let start_rank = if self.side == White { 48..56 } else { 8..16 };
+                return is_pawn
+                    && start_rank.contains(&source)
+                    && forward(source, 1).is_some_and(|sq| self.mailbox[sq] == Piece::NONE)
+                    && forward(source, 2) == Some(target)
+                    && victim == Piece::NONE;
+            }
+            _ => {}
         }
 
-        // Note - get_piece_attacks checks for if a piece CAN BE AN ATTACKER OF A GIVEN SQUARE
-        // Therefore source and target swapped
-        if get_piece_attacks(self, mv.get_target_square(), piece) & (1 << mv.get_source_square()) == 0  {
+        // Quiet moves, captures and promotions
+        if mv.is_capture() {
+            if victim == Piece::NONE || victim.get_side() == self.side {
+                return false;
+            }
+        } else if victim != Piece::NONE {
             return false;
         }
 
-        true
+        if is_pawn {
+            let promotion_rank = if self.side == White { 8..16 } else { 48..56 };
+            if mv.is_promotion() != promotion_rank.contains(&source) {
+                return false;
+            }
+
+            if mv.is_capture() {
+                PAWN_ATTACKS[self.side][source] & (1 << target) != 0
+            } else {
+                forward(source, 1) == Some(target)
+            }
+        } else {
+            // Note - get_piece_attacks checks for if a piece CAN BE AN ATTACKER OF A GIVEN SQUARE
+            // Therefore source and target swapped
+            !mv.is_promotion() && get_piece_attacks(self, target as u8, piece) & (1 << source) != 0
+        }
+    }
+
+    /// Whether the pseudo-legal `mv` checks the opponent's king, directly or by uncovering a slider
+    pub fn gives_check(&self, mv: Move) -> bool {
+        let source = mv.get_source_square() as usize;
+        let target = mv.get_target_square() as usize;
+        let us = 6 * self.side as usize;
+        let king_square = self.bitboards[KING_INDEX[self.side.invert()]].trailing_zeros() as usize;
+
+        // Occupancy after the move, and the piece and square that could check directly
+        let mut occ = self.occupancies[2] & !(1 << source) | (1 << target);
+        let (mut piece, mut square) = (self.mailbox[source], target);
+        // Our pieces that left their squares, the bitboards still have them there
+        let mut moved = 1u64 << source;
+
+        if mv.is_promotion() {
+            piece = mv.get_promoted_piece(self.side);
+        } else if mv.is_enpassant() {
+            occ &= !(1 << if self.side == White { target + 8 } else { target - 8 });
+        } else if mv.get_castling() {
+            // Only the rook can give check
+            let (rook_from, rook_to) = match target {
+                62 => (63, 61),
+                58 => (56, 59),
+                6 => (7, 5),
+                _ => (0, 3),
+            };
+            occ = occ & !(1 << rook_from) | (1 << rook_to);
+            moved |= 1 << rook_from;
+            piece = Piece::new(us + Piece::R as usize);
+            square = rook_to;
+        }
+
+        // Direct check - the checking squares of the piece as seen from the king
+        let direct = match piece as usize % 6 {
+            0 => PAWN_ATTACKS[self.side.invert()][king_square],
+            1 => KNIGHT_ATTACKS[king_square],
+            2 => get_bishop_attacks(king_square, occ),
+            3 => get_rook_attacks(king_square, occ),
+            4 => get_queen_attacks(king_square, occ),
+            _ => 0,
+        };
+        if direct & (1 << square) != 0 {
+            return true;
+        }
+
+        // Discovered check - our other sliders that now see the king
+        let diagonal = (self.bitboards[us + Piece::B as usize] | self.bitboards[us + Piece::Q as usize]) & !moved;
+        let straight = (self.bitboards[us + Piece::R as usize] | self.bitboards[us + Piece::Q as usize]) & !moved;
+
+        get_bishop_attacks(king_square, occ) & diagonal != 0 || get_rook_attacks(king_square, occ) & straight != 0
+    }
+
+    // Same conditions as castling movegen: the right, an empty path and the king not passing through check
+    fn is_castling_pseudo_legal(&self, mv: Move) -> bool {
+        let (king, right, empty, safe): (Piece, Castle, &[usize], [u8; 2]) = match (mv.get_source_square(), mv.get_target_square(), mv.get_move_code()) {
+            (60, 62, MoveCode::KingCastle) => (Piece::K, Castle::Wk, &[61, 62], [60, 61]),
+            (60, 58, MoveCode::QueenCastle) => (Piece::K, Castle::Wq, &[59, 58, 57], [60, 59]),
+            (4, 6, MoveCode::KingCastle) => (Piece::k, Castle::Bk, &[5, 6], [4, 5]),
+            (4, 2, MoveCode::QueenCastle) => (Piece::k, Castle::Bq, &[3, 2, 1], [4, 3]),
+            _ => return false,
+        };
+
+        self.mailbox[mv.get_source_square() as usize] == king
+            && self.castle & right as usize != 0
+            && empty.iter().all(|&square| self.mailbox[square] == Piece::NONE)
+            && safe.iter().all(|&square| !is_square_attacked(square, self))
     }
 
     // print board
@@ -624,6 +763,124 @@ mod tests {
             assert_eq!(after.castle, Castle::Bk as usize | Castle::Bq as usize);
         });
     }
-}
 
+    // Walks the perft tree and checks every incremental key against a full recomputation
+    fn check_keys(board: &BoardPosition, depth: usize) {
+        let keys = compute_hash(board);
+        assert_eq!(board.hash, keys.hash);
+        assert_eq!(board.pawn_hash, keys.pawn_hash);
+        assert_eq!(board.non_pawn_hash, keys.non_pawn_hash);
+        assert_eq!(board.material_hash, keys.material_hash);
+
+        if depth == 0 {
+            return;
+        }
+
+        let null_board = board.make_null_move();
+        assert_eq!(compute_hash(&null_board).hash, null_board.hash);
+        assert_eq!(null_board.material_hash, keys.material_hash);
+
+        for entry in crate::movegen::move_gen::generate_all_moves(board) {
+            if let Some(new_board) = board.make_move(entry.mv) {
+                check_keys(&new_board, depth - 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_incremental_keys() {
+        run_with_big_stack(|| {
+            let fens = [
+                "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+                "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+                "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+                "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+            ];
+
+            for fen in fens {
+                check_keys(&BoardPosition::new(fen), 3);
+            }
+
+            // Same material on different squares shares the material key, but not the others
+            let board = BoardPosition::new("4k3/8/8/8/8/8/4P3/4K2R w K - 0 1");
+            let moved = BoardPosition::new("4k3/8/8/8/8/4P3/8/R3K3 w Q - 0 1");
+            assert_eq!(board.material_hash, moved.material_hash);
+            assert_ne!(board.pawn_hash, moved.pawn_hash);
+            assert_ne!(board.non_pawn_hash[White], moved.non_pawn_hash[White]);
+            assert_eq!(board.non_pawn_hash[Black], moved.non_pawn_hash[Black]);
+        });
+    }
 
+    #[test]
+    fn test_is_pseudo_legal_matches_movegen() {
+        run_with_big_stack(|| {
+            let fens = [
+                "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+                "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+                "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+                "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+                "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8",
+                "rnbqkbnr/ppp1p1pp/8/3pPp2/8/8/PPPP1PPP/RNBQKBNR w KQkq f6 0 3",
+                "r3k2r/8/8/8/8/8/8/R3K2R b KQkq - 0 1",
+            ];
+
+            for fen in fens {
+                let root = BoardPosition::new(fen);
+                let mut positions = vec![root.clone()];
+                for entry in crate::movegen::move_gen::generate_all_moves(&root) {
+                    if let Some(new_board) = root.make_move(entry.mv) {
+                        positions.push(new_board);
+                    }
+                }
+
+                for board in positions {
+                    let mut generated = vec![false; 1 << 16];
+                    for entry in crate::movegen::move_gen::generate_all_moves(&board) {
+                        generated[entry.mv.to_u16() as usize] = true;
+                    }
+
+                    for value in 0..=u16::MAX {
+                        assert_eq!(
+                            board.is_pseudo_legal(Move::from_u16(value)),
+                            generated[value as usize],
+                            "move {:#06x} in {}",
+                            value,
+                            board.to_fen(1)
+                        );
+                    }
+                }
+            }
+        });
+    }
+
+    fn check_gives_check(board: &BoardPosition, depth: usize) {
+        for entry in crate::movegen::move_gen::generate_all_moves(board) {
+            if let Some(new_board) = board.make_move(entry.mv) {
+                assert_eq!(board.gives_check(entry.mv), new_board.is_king_attacked(), "{:?} in {}", entry.mv, board.to_fen(1));
+                if depth > 1 {
+                    check_gives_check(&new_board, depth - 1);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_gives_check() {
+        run_with_big_stack(|| {
+            let fens = [
+                "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+                "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+                "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+                "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8",
+                // Castling into check from the rook, en passant uncovering a bishop
+                "5k2/8/8/8/8/8/8/4K2R w K - 0 1",
+                "6k1/8/8/3pP3/8/1B6/8/4K3 w - d6 0 1",
+                "8/8/8/Q2pP2k/8/8/8/4K3 w - d6 0 1",
+            ];
+
+            for fen in fens {
+                check_gives_check(&BoardPosition::new(fen), 3);
+            }
+        });
+    }
+}
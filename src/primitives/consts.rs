@@ -10,10 +10,13 @@ pub const MVV_LVA : [i32 ; 36] = [
 // Score constants
 pub const MAX_PLY : i32 = 512;
 pub const MAX_PLY_PLUS_ONE : usize = 513;
-pub const NO_SCORE: i32 = 5_000_001;
-pub const MATE_SCORE: i32 = 5_000_000;
+// Scores are stored as i16 in the TT
+pub const NO_SCORE: i32 = 32_001;
+pub const MATE_SCORE: i32 = 32_000;
 pub const MATE_THRESHOLD: i32 = MATE_SCORE - MAX_PLY;
-pub const FIRST_KILLER_BONUS: i32 = 9_000_000;
+// Capture history * scale stays below the MVV_LVA gap between victim types
+pub const CAPTURE_HISTORY_SCALE: i32 = 1024;
 pub const DRAW_SCORE: i32 = 0;
 pub const MIN_DEPTH: usize = 3;
+pub const MAX_DEPTH: usize = 255; // TT stores depth as u8
 pub const MAX_HISTORY : i32 = 16384;
\ No newline at end of file
@@ -1,6 +1,9 @@
 use std::sync::OnceLock;
 
-use crate::primitives::{board::BoardPosition, shared::Color::Black};
+use crate::primitives::{board::BoardPosition, shared::{Color::Black, Piece}};
+
+/// Seed of the key generator. Saved hash files record it, changing it invalidates them.
+pub const ZOBRIST_SEED: u64 = 0xF0E1D2C3B4A59687;
 
 /// Zobrist hash keys for position hashing
 /// Generated once and cached using OnceLock
@@ -18,7 +21,7 @@ pub struct ZobristKeys {
 impl ZobristKeys {
     /// Generate deterministic pseudo-random keys using xorshift
     fn generate() -> Self {
-        let mut seed: u64 = 0xF0E1D2C3B4A59687;
+        let mut seed: u64 = ZOBRIST_SEED;
 
         fn xorshift64(state: &mut u64) -> u64 {
             *state ^= *state << 13;
@@ -55,6 +58,19 @@ impl ZobristKeys {
     }
 }
 
+impl ZobristKeys {
+    /// Summary of the whole key set, catches generator changes that keep the seed
+    pub fn fingerprint(&self) -> u64 {
+        self.piece_keys
+            .iter()
+            .flatten()
+            .chain(std::iter::once(&self.side_key))
+            .chain(&self.castling_keys)
+            .chain(&self.enpassant_keys)
+            .fold(0u64, |acc, &key| acc.rotate_left(7) ^ key)
+    }
+}
+
 /// Global Zobrist keys - initialized once on first access
 static ZOBRIST_KEYS: OnceLock<ZobristKeys> = OnceLock::new();
 
@@ -64,11 +80,21 @@ pub fn get_zobrist_keys() -> &'static ZobristKeys {
     ZOBRIST_KEYS.get_or_init(ZobristKeys::generate)
 }
 
-/// Compute the Zobrist hash for a board position
-/// This is a full re-computation - for incremental updates during search,
-/// use update_hash functions
-pub fn compute_hash(board: &BoardPosition) -> u64 {
+/// Every key of a position, see `BoardPosition` for what each covers
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PositionKeys {
+    pub hash: u64,
+    pub pawn_hash: u64,
+    pub non_pawn_hash: [u64; 2],
+    pub material_hash: u64,
+}
+
+/// Compute the Zobrist keys for a board position
+/// This is a full re-computation - during search they're updated
+/// incrementally in `add_piece`/`remove_piece`
+pub fn compute_hash(board: &BoardPosition) -> PositionKeys {
     let keys = get_zobrist_keys();
+    let mut position_keys = PositionKeys::default();
     let mut hash: u64 = 0;
 
     // Hash pieces
@@ -77,8 +103,18 @@ pub fn compute_hash(board: &BoardPosition) -> u64 {
         while bb != 0 {
             let sq = bb.trailing_zeros() as usize;
             hash ^= keys.piece_keys[piece][sq];
+            if piece == Piece::P as usize || piece == Piece::p as usize {
+                position_keys.pawn_hash ^= keys.piece_keys[piece][sq];
+            } else {
+                position_keys.non_pawn_hash[Piece::new(piece).get_side()] ^= keys.piece_keys[piece][sq];
+            }
             bb &= bb - 1; // Clear LSB
         }
+
+        // The n-th piece of a kind reuses the key of the n-th square
+        for count in 0..board.bitboards[piece].count_ones() as usize {
+            position_keys.material_hash ^= keys.piece_keys[piece][count];
+        }
     }
 
     // Hash side to move
@@ -106,5 +142,6 @@ pub fn compute_hash(board: &BoardPosition) -> u64 {
         hash ^= keys.enpassant_keys[file as usize];
     }
 
-    hash
-}
\ No newline at end of file
+    position_keys.hash = hash;
+    position_keys
+}
@@ -78,6 +78,14 @@ impl Move {
         self.0 == 0
     }
 
+    pub const fn from_u16(value: u16) -> Move {
+        Move(value)
+    }
+
+    pub const fn to_u16(self) -> u16 {
+        self.0
+    }
+
     pub fn get_source_square(self) -> u8 {
         (self.0 & 0x3f) as u8
     }
@@ -166,7 +174,7 @@ impl fmt::Debug for Move {
 
 // encode pieces
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
-#[allow(non_camel_case_types)]
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
 #[repr(u8)]
 pub enum Piece { P = 0, N = 1, B = 2, R = 3, Q = 4, K = 5, p = 6, n = 7, b = 8, r = 9, q = 10, k = 11, NONE = 12}
 
@@ -243,7 +251,7 @@ pub fn coordinates_to_squares(coordinatestr: &str) -> u8 {
     let first = chars.next().unwrap();
     let second = chars.next().unwrap();
     
-    if first.is_ascii_alphabetic() && second.is_digit(10) {
+    if first.is_ascii_alphabetic() && second.is_ascii_digit() {
         match first {
             'a' => val += 0,
             'b' => val += 1,
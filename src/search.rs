@@ -1,15 +1,15 @@
-use std::{vec};
+use std::{thread, vec};
+use std::sync::atomic::Ordering;
 use coarsetime::{Instant};
 
 use crate::evaluation::evaluate::{nnue_evaluate};
-use crate::movegen::move_gen::{is_square_attacked};
+use crate::movegen::move_gen::{checkers, generate_all_moves};
 use crate::movepicker::MovePicker;
 use crate::primitives::board::{BoardPosition};
-use crate::primitives::consts::{DRAW_SCORE, MATE_SCORE, MATE_THRESHOLD, MIN_DEPTH, NO_SCORE};
-use crate::primitives::shared::Color::White;
-use crate::primitives::shared::{Move, Piece, move_to_alg};
+use crate::primitives::consts::{DRAW_SCORE, MATE_SCORE, MATE_THRESHOLD, MAX_DEPTH, MIN_DEPTH, NO_SCORE};
+use crate::primitives::shared::{Move, move_to_alg};
 use crate::search_objs::see::{see_a_move_threshold};
-use crate::search_objs::tt::{TTFlag, score_from_tt};
+use crate::search_objs::tt::{TTFlag, TTStats, score_from_tt};
 use crate::search_objs::search_state::{Reporting, SearchState};
 
 // value is 1024 * depth
@@ -23,7 +23,8 @@ fn lmp_threshold(depth: usize) -> usize {
     3 + depth * depth
 }
 
-pub fn quiescence(board_position: &BoardPosition, search_state: &mut SearchState, alpha: i32, beta: i32, ply: usize) -> i32 {
+// Quiet checks are only tried at the first quiescence ply, so checks can't go on forever
+pub fn quiescence(board_position: &BoardPosition, search_state: &mut SearchState, alpha: i32, beta: i32, ply: usize, quiet_checks: bool) -> i32 {
 
     search_state.seldepth = search_state.seldepth.max(ply);
     search_state.nodes += 1;
@@ -46,50 +47,69 @@ pub fn quiescence(board_position: &BoardPosition, search_state: &mut SearchState
         let score = score_from_tt(entry.score, search_state.ply);
         match entry.flag {
             TTFlag::Exact => {
+                search_state.tt_stats.cutoffs += 1;
                 return score;
             }
 
             TTFlag::Alpha => {
                 if score <= alpha {
+                    search_state.tt_stats.cutoffs += 1;
                     return score;
                 }
             }
 
             TTFlag::Beta => {
                 if score >= beta {
+                    search_state.tt_stats.cutoffs += 1;
                     return score;
                 }
             }
         }
     }
 
-    //PESTO eval
-    let static_eval = if let Some(entry) = probe { entry.eval } else { nnue_evaluate(&board_position, search_state)};
+    let is_in_check = checkers(board_position) != 0;
+    let mut new_alpha = alpha;
+    let mut move_picker = MovePicker::new(tt_move);
 
-    if static_eval >= beta
-    {
-        return beta;
-    }
+    // No standing pat in check, every evasion is searched instead
+    let static_eval = if is_in_check {
+        move_picker.evasions();
+        NO_SCORE
+    } else {
+        //PESTO eval
+        let raw_eval = if let Some(entry) = probe { entry.eval } else { nnue_evaluate(board_position, search_state)};
+        let static_eval = search_state.correction_history.correct(board_position, raw_eval);
 
-    let mut new_alpha = alpha;
+        if static_eval >= beta
+        {
+            return beta;
+        }
 
-    if static_eval > alpha
-    {
-        new_alpha = static_eval;
-    }
+        if static_eval > alpha
+        {
+            new_alpha = static_eval;
+        }
 
-    let mut move_picker = MovePicker::new(tt_move);
+        if quiet_checks {
+            move_picker.quiet_checks();
+        }
+
+        static_eval
+    };
+
+    let mut legal_moves = 0;
 
     while let Some((mv, new_board)) = move_picker.next(board_position, search_state, true) {
+        legal_moves += 1;
 
         // Static Exchange Evaluation Pruning (SEE Pruning)
-        if !see_a_move_threshold(board_position, mv, &new_board, 0) {
+        if !is_in_check && !see_a_move_threshold(board_position, mv, &new_board, 0) {
             continue;
         }
 
         search_state.make_move(mv, board_position, static_eval);
         
-            let res = quiescence(&new_board, search_state, -beta, -new_alpha, ply + 1);
+            let res = quiescence(&new_board, search_state, -beta, -new_alpha, ply + 1, false);
             search_state.take_back();
 
             if -res >= beta {
@@ -110,6 +130,10 @@ pub fn quiescence(board_position: &BoardPosition, search_state: &mut SearchState
             }
         }
 
+    if is_in_check && legal_moves == 0 {
+        return -MATE_SCORE + search_state.ply as i32;
+    }
+
     new_alpha
 }
 
@@ -139,7 +163,7 @@ impl NodeType for NonPV {
 pub fn pvs<NODE: NodeType>(board_position: &BoardPosition, search_state: &mut SearchState, alpha: i32, beta: i32, depth: usize) -> i32 {
     
     if NODE::PV {
-        search_state.pv_table.clear(search_state.ply as usize);
+        search_state.pv_table.clear(search_state.ply);
     }
 
     if search_state.has_occured_in_search(board_position.hash) || search_state.is_trifold_repetition(board_position.hash) || board_position.fifty_mr >= 100 {
@@ -148,10 +172,11 @@ pub fn pvs<NODE: NodeType>(board_position: &BoardPosition, search_state: &mut Se
     }
     
     if depth == 0 {
-        return quiescence(board_position, search_state, alpha, beta, search_state.ply);
+        return quiescence(board_position, search_state, alpha, beta, search_state.ply, true);
     }
 
     search_state.nodes += 1;
+    search_state.publish_nodes();
 
     if search_state.stop_condition.should_hard_quit(search_state.nodes) {
         return 0;  
@@ -176,17 +201,20 @@ pub fn pvs<NODE: NodeType>(board_position: &BoardPosition, search_state: &mut Se
             match entry.flag {
 
                 TTFlag::Exact => {
+                    search_state.tt_stats.cutoffs += 1;
                     return score;
                 }
 
                 TTFlag::Alpha => {
                     if score <= alpha {
+                        search_state.tt_stats.cutoffs += 1;
                         return score;
                     }
                 }
 
                 TTFlag::Beta => {
                     if score >= beta {
+                        search_state.tt_stats.cutoffs += 1;
                         return score;
                     }
                 }
@@ -198,16 +226,16 @@ pub fn pvs<NODE: NodeType>(board_position: &BoardPosition, search_state: &mut Se
     // Static eval
     // ------------------------------------------------------------
 
-    //Todo: move to movegen
-    let our_king = if board_position.side == White { Piece::K } else {Piece::k};
-    let is_in_check = is_square_attacked(board_position.bitboards[our_king as usize].trailing_zeros() as u8, &board_position);
-    let static_eval = nnue_evaluate(board_position, search_state);
+    let is_in_check = checkers(board_position) != 0;
+    // The TT keeps the raw eval, the correction is reapplied on every visit
+    let raw_eval = nnue_evaluate(board_position, search_state);
+    let static_eval = search_state.correction_history.correct(board_position, raw_eval);
 
 
     // Improving is a very important modifier to many heuristics. It checks if our static eval has improved since our last move.
     // As we don't evaluate in check, we look for the first ply we weren't in check between 2 and 4 plies ago. If we find that
     // static eval has improved, or that we were in check both 2 and 4 plies ago, we set improving to true.
-    let improving = if !is_in_check && search_state.move_stack.is_improving(static_eval) {true} else { false }; 
+    let improving = !is_in_check && search_state.move_stack.is_improving(static_eval);
 
     // ------------------------------------------------------------
     // Reverse Futility Pruning (beta pruning)
@@ -231,7 +259,7 @@ pub fn pvs<NODE: NodeType>(board_position: &BoardPosition, search_state: &mut Se
     // ------------------------------------------------------------
     // sf: alpha - 512 - (293 * depth * depth) as i32
     if !NODE::PV && static_eval < alpha - 200 - (100 * depth * depth) as i32{ // likely a fail-low node ?
-        let new_score = quiescence(board_position, search_state, alpha, beta, search_state.ply + 1);
+        let new_score = quiescence(board_position, search_state, alpha, beta, search_state.ply + 1, true);
         if new_score < beta {
             return new_score; // fail soft
         }
@@ -250,7 +278,9 @@ pub fn pvs<NODE: NodeType>(board_position: &BoardPosition, search_state: &mut Se
         {
             let r = 2 + depth / 4; // NMP Reduction
             let null_board = board_position.make_null_move();
-            let search_answer = -pvs::<NonPV>(&null_board, search_state, -beta, -(beta - 1), (depth - r - 1).max(0));
+            search_state.make_null_move(board_position, static_eval);
+            let search_answer = -pvs::<NonPV>(&null_board, search_state, -beta, -(beta - 1), depth - r - 1);
+            search_state.take_back_null_move();
 
             if search_answer >= beta {
                 return search_answer;
@@ -263,12 +293,20 @@ pub fn pvs<NODE: NodeType>(board_position: &BoardPosition, search_state: &mut Se
 
     let mut legal_moves = 0;
     let mut previous_quiet_moves = vec![]; // malus purposes
+    let mut previous_captures = vec![];
+    // A root searched with only some of its moves doesn't give the true root score
+    let store_tt = !NODE::ROOT || !search_state.has_root_restrictions();
     let history_bonus = 300 * depth as i32 - 250;
     
 
-    let mut move_picker = MovePicker::new(tt_move);
+    let mut move_picker = if NODE::ROOT { MovePicker::new_root(tt_move) } else { MovePicker::new(tt_move) };
+    if is_in_check {
+        move_picker.evasions();
+    }
 
     while let Some((mv, new_board)) = move_picker.next(board_position, search_state, false) {
+        let gives_check = board_position.gives_check(mv);
+
         // --------------------------------------------------------
         // Futility pruning
         //
@@ -279,10 +317,10 @@ pub fn pvs<NODE: NodeType>(board_position: &BoardPosition, search_state: &mut Se
         depth <= 5 &&
         legal_moves > 1 &&
         mv.is_quiet() &&
-        !is_in_check {
-            if static_eval + 80 * depth as i32 <= alpha {
-                continue;
-            }
+        !is_in_check &&
+        !gives_check &&
+        static_eval + 80 * depth as i32 <= alpha {
+            continue;
         }
 
         // --------------------------------------------------------
@@ -315,6 +353,9 @@ pub fn pvs<NODE: NodeType>(board_position: &BoardPosition, search_state: &mut Se
         
         let mut score= MATE_SCORE;
 
+        // Continuation histories are keyed by the moves that led here, so read them before mv is on the stack
+        let quiet_score = search_state.get_quiet_score(board_position, mv);
+
         search_state.make_move(mv, board_position, if is_in_check {NO_SCORE} else {static_eval});
 
         legal_moves += 1;
@@ -324,14 +365,14 @@ pub fn pvs<NODE: NodeType>(board_position: &BoardPosition, search_state: &mut Se
         // --------------------------------------------------------
         if depth >= 3 &&
            legal_moves > 2 &&
-           mv.is_quiet() {
+           mv.is_quiet() &&
+           !gives_check {
            // !NODE::PV {
            //and not inCheck
-           //and not givesCheck:
 
             let mut reduction = reduce_lmr_by(depth, legal_moves);
 
-            reduction -= search_state.get_quiet_history(board_position.side, mv) as i32 / 8;
+            reduction -= quiet_score / 16;
 
             let reduction = (reduction / 1024).clamp(0, (depth - 1) as i32) as usize;
 
@@ -363,17 +404,24 @@ pub fn pvs<NODE: NodeType>(board_position: &BoardPosition, search_state: &mut Se
 
                 if score >= beta {
                     
-                    search_state.store_tt(
-                        depth as u8,
-                        score,
-                        static_eval,
-                        TTFlag::Beta,
-                        mv,
-                        board_position.hash
-                    );
+                    if store_tt {
+                        search_state.store_tt(
+                            depth as u8,
+                            score,
+                            raw_eval,
+                            TTFlag::Beta,
+                            mv,
+                            board_position.hash
+                        );
+                    }
+
+                    if !is_in_check {
+                        update_correction_history(board_position, search_state, Some(mv), score, raw_eval, TTFlag::Beta, depth);
+                    }
                     
                     if mv.is_quiet() {
                         search_state.update_killer_move(mv);
+                        search_state.update_countermove(mv);
                         search_state.update_history(board_position, mv, history_bonus);
                         
                         // apply malus to previous quiet moves
@@ -385,6 +433,15 @@ pub fn pvs<NODE: NodeType>(board_position: &BoardPosition, search_state: &mut Se
                             );
                         }
                     }
+
+                    if mv.is_capture() {
+                        search_state.update_capture_history(board_position, mv, history_bonus);
+                    }
+
+                    // Captures that were tried first didn't cut
+                    for prev_mv in &previous_captures {
+                        search_state.update_capture_history(board_position, *prev_mv, -history_bonus);
+                    }
                     
                     return score;
                 }
@@ -396,6 +453,8 @@ pub fn pvs<NODE: NodeType>(board_position: &BoardPosition, search_state: &mut Se
             
         if mv.is_quiet() {
             previous_quiet_moves.push(mv);
+        } else if mv.is_capture() {
+            previous_captures.push(mv);
         }
     }
 
@@ -422,18 +481,39 @@ pub fn pvs<NODE: NodeType>(board_position: &BoardPosition, search_state: &mut Se
         TTFlag::Exact
     };
 
-    search_state.store_tt(
-        depth as u8,
-        best_score,
-        static_eval,
-        flag,
-        best_move.unwrap_or(Move::create_null()),
-        board_position.hash
-    );
+    if !is_in_check {
+        update_correction_history(board_position, search_state, best_move, best_score, raw_eval, flag, depth);
+    }
+
+    if store_tt {
+        search_state.store_tt(
+            depth as u8,
+            best_score,
+            raw_eval,
+            flag,
+            best_move.unwrap_or(Move::create_null()),
+            board_position.hash
+        );
+    }
 
     best_score
 }
 
+// Teaches the correction history how far off the uncorrected eval was. Bounds only count when they point the right way
+// past the corrected eval, and captures or mates say little about how good the eval of a quiet position is.
+fn update_correction_history(board_position: &BoardPosition, search_state: &mut SearchState, best_move: Option<Move>, best_score: i32, raw_eval: i32, flag: TTFlag, depth: usize) {
+    let static_eval = search_state.correction_history.correct(board_position, raw_eval);
+
+    if best_move.is_some_and(|mv| mv.is_capture())
+        || best_score.abs() >= MATE_THRESHOLD
+        || (flag == TTFlag::Beta && best_score <= static_eval)
+        || (flag == TTFlag::Alpha && best_score >= static_eval) {
+        return;
+    }
+
+    search_state.correction_history.update(board_position, depth, best_score - raw_eval);
+}
+
 pub fn score_to_mate( score: i32 ) -> i32 {
     let distance = MATE_SCORE - score.abs();
     if score > 0 {
@@ -446,7 +526,7 @@ pub fn collect_pv(moves: &[Move]) -> String {
     moves
         .iter()
         .filter(|&&mv| mv != Move::create_null())
-        .map(|x| move_to_alg(x))
+        .map(move_to_alg)
         .reduce(|a, b| a + " " + &b)
         .unwrap_or_default()
 }
@@ -486,60 +566,223 @@ pub fn single_depth_search_aspirated(board_position: &BoardPosition, search_stat
 }
 
 
-pub fn search(board_position: &BoardPosition, search_state: &mut SearchState) {
+/// Outcome of one thread's iterative deepening
+#[derive(Debug, Clone)]
+pub struct SearchResult {
+    pub score: i32,
+    pub depth: usize,
+    pub seldepth: usize,
+    pub pv: Vec<Move>,
+    pub ponder_move: Option<Move>,
+}
 
-    search_state.stop_condition.started_search = Instant::now();
+impl SearchResult {
+    pub fn best_move(&self) -> Move {
+        self.pv.first().copied().unwrap_or(Move::create_null())
+    }
 
-    search_state.reset_for_new_iteration(MIN_DEPTH);
+    // Lazy SMP vote: a helper only wins if it got deeper without finding a worse score
+    fn is_better_than(&self, other: &SearchResult) -> bool {
+        !self.best_move().is_null() && self.depth > other.depth && self.score >= other.score
+    }
+}
 
-    let mut score = single_depth_search(board_position, search_state, MIN_DEPTH);
-        
-    print_info_string(score, search_state);
-        
-    let mut depth = MIN_DEPTH;
-    let mut bestmove = search_state.pv_table.table[0][0];
-    search_state.reset_for_new_iteration(depth);        
+pub fn iterative_deepening(board_position: &BoardPosition, search_state: &mut SearchState) -> SearchResult {
+    // Helpers start at staggered depths so the threads don't all search the same tree
+    let mut depth = MIN_DEPTH + search_state.thread_id % 2;
+    // Only the main thread reports, so only the main thread searches extra lines
+    let multi_pv = if search_state.thread_id == 0 { search_state.engine_config.multi_pv.max(1) } else { 1 };
+
+    let mut lines: Vec<SearchResult> = Vec::with_capacity(multi_pv);
+
+    loop {
+        let mut new_lines: Vec<SearchResult> = Vec::with_capacity(multi_pv);
+
+        // MultiPV - every line searches the root without the best moves of the lines before it
+        for pv_index in 0..multi_pv {
+            search_state.reset_for_new_iteration(depth);
+
+            let score = match lines.get(pv_index) {
+                Some(line) => single_depth_search_aspirated(board_position, search_state, depth, line.score),
+                None => single_depth_search(board_position, search_state, depth),
+            };
+
+            // The very first line is kept even if interrupted, we need some move to play
+            let is_first_line = lines.is_empty() && new_lines.is_empty();
+            if search_state.stop_condition.should_hard_quit(search_state.nodes) && !is_first_line {
+                break;
+            }
+
+            // Fewer legal moves than lines requested
+            if pv_index > 0 && search_state.pv_table.len[0] == 0 {
+                break;
+            }
+
+            new_lines.push(completed_iteration(board_position, search_state, score, depth));
+            search_state.root_excluded.push(search_state.pv_table.table[0][0]);
+        }
+
+        search_state.root_excluded.clear();
+
+        if new_lines.is_empty() {
+            break;
+        }
+
+        // A later line can come out better than an earlier one after an aspiration re-search
+        new_lines.sort_by_key(|line| std::cmp::Reverse(line.score));
+        lines = merge_lines(new_lines, lines);
+
+        for (pv_index, line) in lines.iter().enumerate() {
+            print_info_line(line, pv_index + 1, search_state);
+        }
+
+        if depth >= MAX_DEPTH || search_state.stop_condition.should_soft_quit(depth, search_state.nodes) || search_state.stop_condition.should_hard_quit(search_state.nodes) {
+            break;
+        }
 
-    while !search_state.stop_condition.should_soft_quit(depth, search_state.nodes) && !search_state.stop_condition.should_hard_quit(search_state.nodes) {
         depth += 1;
-        search_state.reset_for_new_iteration(depth);        
-        
-        let new_score = single_depth_search_aspirated(board_position, search_state, depth, score);
+    }
+
+    search_state.publish_nodes();
+    lines.swap_remove(0)
+}
+
+// A stop part-way through the lines of a depth leaves the unfinished ones to the previous depth,
+// skipping those whose move a finished line already plays
+fn merge_lines(mut new_lines: Vec<SearchResult>, previous: Vec<SearchResult>) -> Vec<SearchResult> {
+    let line_count = new_lines.len().max(previous.len());
+
+    for line in previous {
+        if new_lines.len() == line_count {
+            break;
+        }
+        if new_lines.iter().all(|new_line| new_line.best_move() != line.best_move()) {
+            new_lines.push(line);
+        }
+    }
+
+    new_lines
+}
+
+fn completed_iteration(board_position: &BoardPosition, search_state: &mut SearchState, score: i32, depth: usize) -> SearchResult {
+    let len = search_state.pv_table.len[0];
 
-        //if search_state.search_stage == Full {
-        if !search_state.stop_condition.should_hard_quit(search_state.nodes) {
-            score = new_score;
-            print_info_string(score, search_state);
-            bestmove = search_state.pv_table.table[0][0];
+    SearchResult {
+        score,
+        depth,
+        seldepth: search_state.seldepth,
+        pv: search_state.pv_table.table[0][..len].to_vec(),
+        ponder_move: get_ponder_move(board_position, search_state),
+    }
+}
+
+pub fn search(board_position: &BoardPosition, search_state: &mut SearchState) {
+
+    search_state.stop_condition.started_search = Instant::now();
+    search_state.tt_stats = TTStats::default();
+
+    // Lazy SMP - helpers search the same position and only talk to the main thread through the TT
+    let mut helpers = std::mem::take(&mut search_state.helpers);
+    let helper_signals = search_state.helper_signals();
+    helper_signals.stop.store(false, Ordering::Relaxed);
+
+    let (mut result, from_helper) = thread::scope(|scope| {
+        let handles: Vec<_> = helpers
+            .iter_mut()
+            .map(|helper| {
+                helper.sync_helper(board_position, &search_state.move_stack, &search_state.search_moves);
+                thread::Builder::new()
+                    .stack_size(80 * 1024 * 1024)
+                    .spawn_scoped(scope, move || iterative_deepening(board_position, helper))
+                    .unwrap()
+            })
+            .collect();
+
+        let mut result = iterative_deepening(board_position, search_state);
+
+        search_state.stop_condition.wait_until_bounded();
+        helper_signals.stop.store(true, Ordering::Relaxed);
+
+        let mut from_helper = false;
+        for handle in handles {
+            let helper_result = handle.join().unwrap();
+            // With MultiPV the reported lines all come from the main thread
+            if search_state.engine_config.multi_pv <= 1 && helper_result.is_better_than(&result) {
+                result = helper_result;
+                from_helper = true;
+            }
         }
+
+        (result, from_helper)
+    });
+
+    search_state.helpers = helpers;
+
+    if from_helper {
+        // A helper went deeper, report its line as the final one
+        print_info_line(&result, 1, search_state);
+    }
+
+    let mut bestmove = result.best_move();
+
+    if bestmove.is_null() {
+        // Stopped before the first iteration got through a single root move
+        bestmove = generate_all_moves(board_position)
+            .into_iter()
+            .map(|entry| entry.mv)
+            .find(|&mv| search_state.is_root_move_allowed(mv) && board_position.make_move(mv).is_some())
+            .unwrap_or(bestmove);
+        result.ponder_move = None;
     }
 
     if search_state.reporting != Reporting::Quiet {
-        println!("bestmove {}", move_to_alg(&bestmove));
+        match result.ponder_move {
+            Some(ponder_move) => println!("bestmove {} ponder {}", move_to_alg(&bestmove), move_to_alg(&ponder_move)),
+            None => println!("bestmove {}", move_to_alg(&bestmove)),
+        }
     }
 
     // search_state.print_history_stats();
     
 }
 
-pub fn print_info_string(score: i32, search_state: &SearchState) {
+// Second move of the root PV - the reply we expect and can ponder on
+fn get_ponder_move(board_position: &BoardPosition, search_state: &mut SearchState) -> Option<Move> {
+    match search_state.pv_table.len[0] {
+        0 => None,
+        1 => {
+            // PV cut short by a TT hit, the reply stored in the TT is the next best guess
+            let new_board = board_position.make_move(search_state.pv_table.table[0][0])?;
+            let reply = search_state.probe_tt(new_board.hash)?.best_move;
+
+            if reply.is_null() || !new_board.is_pseudo_legal(reply) || new_board.make_move(reply).is_none() {
+                return None;
+            }
+
+            Some(reply)
+        },
+        _ => Some(search_state.pv_table.table[0][1]),
+    }
+}
+
+fn print_info_line(result: &SearchResult, multi_pv: usize, search_state: &SearchState) {
     if search_state.reporting == Reporting::Quiet {
         return;
     }
     
-    let len = search_state.pv_table.len[0];
-    let pv: String = collect_pv(&search_state.pv_table.table[0][..len]);
+    let pv: String = collect_pv(&result.pv);
+    let nodes = search_state.total_nodes();
 
     let micros = if search_state.stop_condition.started_search.elapsed().as_micros() > 0 {search_state.stop_condition.started_search.elapsed().as_micros()} else {1};
 
-    if score.abs() > MATE_THRESHOLD {
-        let mate = score_to_mate( score );
-        println!("info score mate {} depth {} seldepth {} nodes {} time {} nps {} pv {}", mate, search_state.max_depth, 
-            search_state.seldepth, search_state.nodes, micros/1000, search_state.nodes * 1000000 / micros, pv);
+    if result.score.abs() > MATE_THRESHOLD {
+        let mate = score_to_mate( result.score );
+        println!("info multipv {} score mate {} depth {} seldepth {} nodes {} time {} nps {} hashfull {} pv {}", multi_pv, mate, result.depth, 
+            result.seldepth, nodes, micros/1000, nodes * 1000000 / micros, search_state.hashfull(), pv);
     }
     else {
-        println!("info score cp {} depth {} seldepth {} nodes {} time {} nps {} pv {}", score, search_state.max_depth, 
-            search_state.seldepth, search_state.nodes, micros/1000, search_state.nodes * 1000000 / micros, pv);
+        println!("info multipv {} score cp {} depth {} seldepth {} nodes {} time {} nps {} hashfull {} pv {}", multi_pv, result.score, result.depth, 
+            result.seldepth, nodes, micros/1000, nodes * 1000000 / micros, search_state.hashfull(), pv);
     }
 }
 
@@ -548,10 +791,13 @@ pub fn print_info_string(score: i32, search_state: &SearchState) {
 mod tests {
 
     use std::thread;
-    use crate::gui::parse_position_command;
-    use crate::search::{search, single_depth_search};
+    use crate::gui::{parse_move, parse_position_command};
+    use crate::primitives::board::BoardPosition;
+    use crate::movepicker::MovePicker;
+    use crate::search::{SearchResult, iterative_deepening, merge_lines, search, single_depth_search};
+    use crate::primitives::shared::{START_POSITION, move_to_alg};
     use crate::search_objs::config::EngineConfig;
-use crate::search_objs::search_state::SearchState;
+use crate::search_objs::search_state::{Reporting, SearchState};
 
 
     #[test]
@@ -650,4 +896,114 @@ use crate::search_objs::search_state::SearchState;
             .unwrap();
         handler.join().unwrap();
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_lazy_smp_search() {
+        let builder = thread::Builder::new().stack_size(80 * 1024 * 1024);
+        let handler = builder
+            .spawn(|| {
+                let mut engine_config = EngineConfig::thin();
+                engine_config.threads = 3;
+                let mut search_state = SearchState::new(&engine_config);
+                search_state.reporting = Reporting::Quiet;
+                assert_eq!(search_state.helpers.len(), 2);
+
+                let board_position = parse_position_command(&mut search_state, "position startpos moves e2e4 e7e5");
+                search_state.stop_condition.depth = Some(8);
+                search(&board_position, &mut search_state);
+
+                // Helpers are handed back after the search and their nodes are counted
+                assert_eq!(search_state.helpers.len(), 2);
+                assert!(search_state.helpers.iter().all(|helper| helper.nodes > 0));
+                assert_eq!(search_state.total_nodes(), search_state.nodes + search_state.helpers.iter().map(|helper| helper.nodes).sum::<u64>());
+            })
+            .unwrap();
+        handler.join().unwrap();
+    }
+
+    #[test]
+    fn test_multi_pv_excludes_earlier_lines() {
+        let builder = thread::Builder::new().stack_size(80 * 1024 * 1024);
+        let handler = builder
+            .spawn(|| {
+                let mut engine_config = EngineConfig::thin();
+                engine_config.multi_pv = 3;
+                let mut search_state = SearchState::new(&engine_config);
+                search_state.reporting = Reporting::Quiet;
+
+                // Ra8# is the only mate, the other lines have to find something else
+                let board_position = parse_position_command(&mut search_state, "position fen 7k/8/6K1/8/8/8/8/R7 w - - 0 1");
+                search_state.stop_condition.depth = Some(5);
+                let result = iterative_deepening(&board_position, &mut search_state);
+                assert_eq!(move_to_alg(&result.best_move()), "a1a8");
+                assert!(search_state.root_excluded.is_empty());
+
+                let best_move = result.best_move();
+                search_state.root_excluded.push(best_move);
+                search_state.reset_for_new_iteration(4);
+                let score = single_depth_search(&board_position, &mut search_state, 4);
+                assert_ne!(search_state.pv_table.table[0][0], best_move);
+                assert!(score < result.score);
+            })
+            .unwrap();
+        handler.join().unwrap();
+    }
+
+    #[test]
+    fn test_lmr_history_matches_move_ordering() {
+        let builder = thread::Builder::new().stack_size(80 * 1024 * 1024);
+        let handler = builder
+            .spawn(|| {
+                let mut search_state = SearchState::new(&EngineConfig::thin());
+                let root = parse_position_command(&mut search_state, "position startpos");
+
+                // Node after 1. e4, where Nf6 has a trained continuation history
+                let e4 = parse_move(&root, "e2e4").unwrap();
+                search_state.make_move(e4, &root, 0);
+                let board_position = root.make_move(e4).unwrap();
+                let mv = parse_move(&board_position, "g8f6").unwrap();
+                for _ in 0..4 {
+                    search_state.update_history(&board_position, mv, 2000);
+                }
+
+                // What pvs reads for LMR, before making the move
+                let quiet_score = search_state.get_quiet_score(&board_position, mv);
+                assert_eq!(quiet_score, MovePicker::get_move_score(&board_position, &search_state, mv));
+
+                // Once mv is on the stack the continuation keys point elsewhere
+                search_state.make_move(mv, &board_position, 0);
+                assert_ne!(search_state.get_quiet_score(&board_position, mv), quiet_score);
+            })
+            .unwrap();
+        handler.join().unwrap();
+    }
+
+    #[test]
+    fn test_interrupted_multi_pv_keeps_previous_lines() {
+        let builder = thread::Builder::new().stack_size(80 * 1024 * 1024);
+        let handler = builder
+            .spawn(|| {
+                let line = |mv: &str, score: i32, depth: usize| SearchResult {
+                    score,
+                    depth,
+                    seldepth: depth,
+                    pv: vec![parse_move(&BoardPosition::new(START_POSITION), mv).unwrap()],
+                    ponder_move: None,
+                };
+                let previous = vec![line("e2e4", 40, 7), line("d2d4", 30, 7), line("g1f3", 20, 7)];
+
+                // Stopped after the first line of depth 8 - it moved d2d4 up, e2e4 and g1f3 stay from depth 7
+                let merged = merge_lines(vec![line("d2d4", 45, 8)], previous.clone());
+                let summary: Vec<_> = merged.iter().map(|line| (move_to_alg(&line.best_move()), line.depth)).collect();
+                assert_eq!(summary, [("d2d4".to_string(), 8), ("e2e4".to_string(), 7), ("g1f3".to_string(), 7)]);
+
+                // A finished depth replaces everything
+                let finished = vec![line("c2c4", 50, 8), line("e2e4", 45, 8), line("d2d4", 10, 8)];
+                assert_eq!(merge_lines(finished, previous.clone()).iter().map(|line| line.depth).collect::<Vec<_>>(), [8, 8, 8]);
+
+                assert_eq!(merge_lines(Vec::new(), previous).len(), 3);
+            })
+            .unwrap();
+        handler.join().unwrap();
+    }
+}
@@ -1,9 +1,19 @@
+use std::sync::Arc;
+
+use crate::evaluation::nnue::{Network, embedded_network};
+
 #[derive(Clone)]
 pub struct EngineConfig {
     pub hash: usize, // In megabytes
     /// Soft node limit from the `SoftNodes` UCI option.
     /// `None` means no soft node limit (option set to 0).
     pub soft_nodes: Option<u64>,
+    /// Search threads from the `Threads` UCI option (Lazy SMP).
+    pub threads: usize,
+    /// Root lines searched and reported, from the `MultiPV` UCI option.
+    pub multi_pv: usize,
+    /// Active net, the embedded one unless set by the `EvalFile` UCI option.
+    pub network: Arc<Network>,
 }
 
 impl Default for EngineConfig {
@@ -11,6 +21,9 @@ impl Default for EngineConfig {
         EngineConfig {
             hash: 256,
             soft_nodes: None,
+            threads: 1,
+            multi_pv: 1,
+            network: embedded_network(),
         }
     }
 }
@@ -22,6 +35,9 @@ impl EngineConfig {
         EngineConfig {
             hash: 16,
             soft_nodes: None,
+            threads: 1,
+            multi_pv: 1,
+            network: embedded_network(),
         }
     }
 }
\ No newline at end of file
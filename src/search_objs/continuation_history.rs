@@ -0,0 +1,64 @@
+use crate::primitives::consts::MAX_HISTORY;
+use crate::primitives::shared::Piece;
+
+/// History of a quiet move given the move played some plies before it,
+/// indexed [previous piece][previous target][piece][target]
+pub struct ContinuationHistory {
+    table: Vec<[[[i16; 64]; 12]; 64]>,
+}
+
+impl Default for ContinuationHistory {
+    fn default() -> Self {
+        Self {
+            table: vec![[[[0; 64]; 12]; 64]; 12],
+        }
+    }
+}
+
+impl ContinuationHistory {
+    pub fn clear(&mut self) {
+        for entry in self.table.iter_mut() {
+            *entry = [[[0; 64]; 12]; 64];
+        }
+    }
+
+    #[inline(always)]
+    pub fn get(&self, previous: (Piece, u8), piece: Piece, target: u8) -> i16 {
+        self.table[previous.0 as usize][previous.1 as usize][piece as usize][target as usize]
+    }
+
+    pub fn update(&mut self, previous: (Piece, u8), piece: Piece, target: u8, bonus: i32) {
+        let clamped_bonus = bonus.clamp(-MAX_HISTORY, MAX_HISTORY);
+        let entry = &mut self.table[previous.0 as usize][previous.1 as usize][piece as usize][target as usize];
+        *entry += (clamped_bonus - *entry as i32 * clamped_bonus.abs() / MAX_HISTORY) as i16;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::primitives::consts::MAX_HISTORY;
+    use crate::primitives::shared::Piece;
+    use crate::search_objs::continuation_history::ContinuationHistory;
+
+    #[test]
+    fn test_continuation_history() {
+        let mut continuation_history = ContinuationHistory::default();
+        let previous = (Piece::p, 28);
+
+        for _ in 0..100 {
+            continuation_history.update(previous, Piece::N, 45, 2000);
+        }
+        let value = continuation_history.get(previous, Piece::N, 45);
+        assert!(value > 0 && value as i32 <= MAX_HISTORY);
+
+        // Other previous moves and other replies are untouched
+        assert_eq!(continuation_history.get((Piece::p, 27), Piece::N, 45), 0);
+        assert_eq!(continuation_history.get(previous, Piece::B, 45), 0);
+
+        continuation_history.update(previous, Piece::N, 45, -MAX_HISTORY * 4);
+        assert!(continuation_history.get(previous, Piece::N, 45) < value);
+
+        continuation_history.clear();
+        assert_eq!(continuation_history.get(previous, Piece::N, 45), 0);
+    }
+}
@@ -0,0 +1,116 @@
+use crate::primitives::board::BoardPosition;
+use crate::primitives::consts::MATE_THRESHOLD;
+use crate::primitives::shared::Color::{Black, White};
+
+/// Entries per side to move in every table
+const CORRECTION_HISTORY_SIZE: usize = 16384;
+/// Entries are stored in 1/GRAIN of a centipawn
+const CORRECTION_GRAIN: i32 = 256;
+const MAX_CORRECTION: i32 = 128 * CORRECTION_GRAIN;
+/// Out of 256, how much a single update at high depth moves an entry
+const MAX_UPDATE_WEIGHT: i32 = 16;
+
+/// How far static eval was off from search results in similar positions - keyed by pawn structure
+/// and by each side's other pieces. Added to the static eval before it's used for pruning.
+pub struct CorrectionHistory {
+    // Indexed [side to move][key % size]
+    pawn: Vec<[i32; CORRECTION_HISTORY_SIZE]>,
+    // Indexed [piece color][side to move][key % size]
+    non_pawn: [Vec<[i32; CORRECTION_HISTORY_SIZE]>; 2],
+}
+
+impl Default for CorrectionHistory {
+    fn default() -> Self {
+        Self {
+            pawn: vec![[0; CORRECTION_HISTORY_SIZE]; 2],
+            non_pawn: [vec![[0; CORRECTION_HISTORY_SIZE]; 2], vec![[0; CORRECTION_HISTORY_SIZE]; 2]],
+        }
+    }
+}
+
+impl CorrectionHistory {
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    #[inline(always)]
+    fn index(key: u64) -> usize {
+        key as usize % CORRECTION_HISTORY_SIZE
+    }
+
+    // Pawn, white non-pawn and black non-pawn keys
+    fn keys(board_position: &BoardPosition) -> [u64; 3] {
+        [
+            board_position.pawn_hash,
+            board_position.non_pawn_hash[White],
+            board_position.non_pawn_hash[Black],
+        ]
+    }
+
+    /// Static eval with what the tables learned about this position added. The pawn table gets half the weight
+    /// and each non-pawn table a quarter, so entries that all learned the same error add up to that error.
+    pub fn correct(&self, board_position: &BoardPosition, static_eval: i32) -> i32 {
+        let side = board_position.side as usize;
+        let [pawn_key, white_key, black_key] = Self::keys(board_position);
+
+        let correction = (2 * self.pawn[side][Self::index(pawn_key)]
+            + self.non_pawn[White][side][Self::index(white_key)]
+            + self.non_pawn[Black][side][Self::index(black_key)]) / 4;
+
+        (static_eval + correction / CORRECTION_GRAIN).clamp(-MATE_THRESHOLD + 1, MATE_THRESHOLD - 1)
+    }
+
+    /// Moves the entries towards `error`, the search score minus the uncorrected static eval. Deeper searches weigh more.
+    pub fn update(&mut self, board_position: &BoardPosition, depth: usize, error: i32) {
+        let side = board_position.side as usize;
+        let [pawn_key, white_key, black_key] = Self::keys(board_position);
+        let target = error * CORRECTION_GRAIN;
+        let weight = (depth as i32 + 1).min(MAX_UPDATE_WEIGHT);
+
+        let update = |entry: &mut i32| {
+            *entry = ((*entry * (256 - weight) + target * weight) / 256).clamp(-MAX_CORRECTION, MAX_CORRECTION);
+        };
+
+        update(&mut self.pawn[side][Self::index(pawn_key)]);
+        update(&mut self.non_pawn[White][side][Self::index(white_key)]);
+        update(&mut self.non_pawn[Black][side][Self::index(black_key)]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use crate::primitives::board::BoardPosition;
+    use crate::primitives::shared::START_POSITION;
+    use crate::search_objs::correction_history::CorrectionHistory;
+
+    #[test]
+    fn test_correction_history() {
+        let builder = thread::Builder::new().stack_size(80 * 1024 * 1024);
+        let handler = builder
+            .spawn(|| {
+                let mut correction_history = CorrectionHistory::default();
+                let board_position = BoardPosition::new(START_POSITION);
+                let other_pawns = BoardPosition::new("rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR w KQkq - 0 1");
+                let other_side = BoardPosition::new("rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR b KQkq - 0 1");
+                assert_eq!(correction_history.correct(&board_position, 10), 10);
+
+                // Search keeps finding the position 50 better than the eval, well within MAX_CORRECTION
+                for _ in 0..200 {
+                    correction_history.update(&board_position, 10, 50);
+                }
+                let corrected = correction_history.correct(&board_position, 10);
+                assert!((58..=60).contains(&corrected), "corrected to {corrected}");
+
+                // Only the non-pawn tables match, and nothing for the other side to move
+                let partly = correction_history.correct(&other_pawns, 10);
+                assert!(partly > 10 && partly < corrected);
+                assert_eq!(correction_history.correct(&other_side, 10), 10);
+
+                correction_history.clear();
+                assert_eq!(correction_history.correct(&board_position, 10), 10);
+            })
+            .unwrap();
+        handler.join().unwrap();
+    }
+}
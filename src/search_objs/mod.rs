@@ -1,6 +1,9 @@
 pub mod tt;
+pub mod correction_history;
+pub mod continuation_history;
 pub mod move_stack;
 pub mod search_state;
 pub mod config;
 pub mod see;
-pub mod pv_table;
\ No newline at end of file
+pub mod pv_table;
+pub mod search_thread;
\ No newline at end of file
@@ -1,10 +1,11 @@
 use arrayvec::ArrayVec;
 
 use crate::primitives::consts::NO_SCORE;
+use crate::primitives::shared::{Move, Piece};
 
 /// Threefold repetition detector
 /// Stores a history of position hashes
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct MoveStack {
     position_command_hashes: Vec<u64>,
     search_position_info: ArrayVec<PositionInfo, 513>
@@ -30,10 +31,10 @@ impl MoveStack {
         self.position_command_hashes.push(hash);
     }
 
-    /// Push a position onto the history
+    /// Push a position onto the history, along with the move played from it
     #[inline(always)]
-    pub fn push(&mut self, hash: u64, static_eval: i32) {
-        self.search_position_info.push(PositionInfo { hash: hash, static_eval: static_eval });
+    pub fn push(&mut self, hash: u64, static_eval: i32, mv: Move, piece: Piece) {
+        self.search_position_info.push(PositionInfo { hash, static_eval, mv, piece });
     }
 
     /// Pop the last position from history
@@ -95,6 +96,19 @@ impl MoveStack {
 
         false
     }
+
+    /// The move played `plies_ago` plies before the current position (1 is the last one) and the piece that moved.
+    /// None before the search root and for null moves.
+    #[inline(always)]
+    pub fn previous_move(&self, plies_ago: usize) -> Option<(Piece, Move)> {
+        let info = self.search_position_info.iter().rev().nth(plies_ago - 1)?;
+
+        if info.mv.is_null() {
+            return None;
+        }
+
+        Some((info.piece, info.mv))
+    }
 }
 
 impl Default for MoveStack {
@@ -103,8 +117,10 @@ impl Default for MoveStack {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct PositionInfo {
     hash: u64,
     static_eval: i32,
+    mv: Move,
+    piece: Piece,
 }
\ No newline at end of file
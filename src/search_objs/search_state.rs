@@ -1,24 +1,38 @@
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
 use coarsetime::{Instant};
 
 use crate::primitives::board::BoardPosition;
 use crate::primitives::shared::{Color, Move, Piece};
 use crate::primitives::consts::{MAX_HISTORY, MVV_LVA};
 use crate::search_objs::config::EngineConfig;
+use crate::search_objs::continuation_history::ContinuationHistory;
+use crate::search_objs::correction_history::CorrectionHistory;
 use crate::search_objs::move_stack::MoveStack;
 use crate::search_objs::pv_table::PrincipalVariationTable;
 use crate::search_objs::search_state::Reporting::UCI;
-use crate::search_objs::tt::{TTEntry, TTFlag, TranspositionTable, score_to_tt};
+use crate::search_objs::tt::{TTEntry, TTFlag, TTStats, TranspositionTable, score_to_tt};
 use crate::evaluation::network_state::NetworkState;
 
 /// Search state structure - encapsulates all search-related state
 pub struct SearchState {
     pub max_depth: usize, // Of the search iteration, not in general
     pub seldepth: usize,
-    pub killer_moves: [Move; 256],
+    pub killer_moves: [[Move; 2]; 256],
     //only public for test purposes
     pub history_moves: [[[i16; 64]; 64]; 2],
-    //pub capt_history_moves: [[[i32; 64]; 12]; 12], // target, own, captured
-    tt: TranspositionTable,
+    pub capt_history_moves: [[[i16; 6]; 64]; 12], // own, target, captured type
+    // History of a quiet move after the moves 1 and 2 plies before it
+    pub continuation_history: [ContinuationHistory; 2],
+    // Quiet move that refuted a move, indexed [piece][target] of that move
+    pub countermoves: [[Move; 64]; 12],
+    pub correction_history: CorrectionHistory,
+    tt: Arc<TranspositionTable>,
+    pub tt_stats: TTStats,
     pub move_stack: MoveStack,
     pub nodes: u64,
     pub stop_condition: StopCondition,
@@ -29,40 +43,116 @@ pub struct SearchState {
     pub engine_config: EngineConfig,
     pub reporting: Reporting,
     //pub search_stage: SearchStage,
+    // Lazy SMP. 0 is the main thread, which owns the helpers' states between searches
+    pub thread_id: usize,
+    // MultiPV - root moves the current line may not play
+    pub root_excluded: Vec<Move>,
+    // go searchmoves - the only root moves to consider, empty means all of them
+    pub search_moves: Vec<Move>,
+    pub helpers: Vec<SearchState>,
+    helper_nodes: Vec<Arc<AtomicU64>>,
+    // Stops the helpers once the main thread is done
+    helper_signals: Arc<SearchSignals>,
+    // This thread's node count, readable from the main thread during search
+    thread_nodes: Arc<AtomicU64>,
 }
 
 impl SearchState {
     pub fn new(config: &EngineConfig) -> Self {
+        let tt = Arc::new(TranspositionTable::new(config.hash));
+        let mut search_state = Self::new_thread(config, tt, Arc::default(), 0);
+
+        search_state.helpers = (1..config.threads.max(1))
+            .map(|thread_id| Self::new_thread(config, search_state.tt.clone(), search_state.helper_signals.clone(), thread_id))
+            .collect();
+        search_state.helper_nodes = search_state.helpers.iter().map(|helper| helper.thread_nodes.clone()).collect();
+
+        search_state
+    }
+
+    fn new_thread(config: &EngineConfig, tt: Arc<TranspositionTable>, signals: Arc<SearchSignals>, thread_id: usize) -> Self {
         Self {
             max_depth: 0,
             seldepth: 0,
-            killer_moves: [Move::create_null(); 256],
+            killer_moves: [[Move::create_null(); 2]; 256],
             history_moves: [[[0; 64]; 64]; 2],
-            //capt_history_moves: [[[0; 64]; 12]; 12],
-            tt: TranspositionTable::new(config.hash),
+            capt_history_moves: [[[0; 6]; 64]; 12],
+            continuation_history: Default::default(),
+            countermoves: [[Move::create_null(); 64]; 12],
+            correction_history: CorrectionHistory::default(),
+            tt,
+            tt_stats: TTStats::default(),
             move_stack: MoveStack::new(),
             nodes: 0,
-            stop_condition: StopCondition::default(),
+            stop_condition: StopCondition::new(signals),
             //deadline: Instant::now().checked_add(Duration::from_secs(1)).unwrap(),
             should_quit: false,
             ply: 0,
-            network_state: NetworkState::default(),
+            network_state: NetworkState::new(&config.network),
             pv_table: PrincipalVariationTable::default(),
             engine_config: config.clone(),
             reporting: UCI,
             //search_stage: Meaningless
+            thread_id,
+            root_excluded: Vec::new(),
+            search_moves: Vec::new(),
+            helpers: Vec::new(),
+            helper_nodes: Vec::new(),
+            helper_signals: Arc::default(),
+            thread_nodes: Arc::default(),
         }
     }
 
+    // Lazy SMP - a helper starts every search from the main thread's position and game history
+    pub fn sync_helper(&mut self, board_position: &BoardPosition, move_stack: &MoveStack, search_moves: &Vec<Move>) {
+        self.max_depth = 0;
+        self.seldepth = 0;
+        self.killer_moves = [[Move::create_null(); 2]; 256];
+        self.move_stack.clone_from(move_stack);
+        self.search_moves.clone_from(search_moves);
+        self.nodes = 0;
+        self.thread_nodes.store(0, Ordering::Relaxed);
+        self.tt_stats = TTStats::default();
+        self.pv_table.clear(0);
+        // Helpers search until the main thread stops them
+        self.stop_condition = StopCondition::new(self.stop_condition.signals());
+        self.stop_condition.infinite = true;
+        self.ply = 0;
+        self.network_state.start_board(board_position, &self.engine_config.network);
+        self.reporting = Reporting::Quiet;
+    }
+
+    pub fn is_root_move_allowed(&self, mv: Move) -> bool {
+        (self.search_moves.is_empty() || self.search_moves.contains(&mv)) && !self.root_excluded.contains(&mv)
+    }
+
+    pub fn has_root_restrictions(&self) -> bool {
+        !self.search_moves.is_empty() || !self.root_excluded.is_empty()
+    }
+
+    pub fn helper_signals(&self) -> Arc<SearchSignals> {
+        self.helper_signals.clone()
+    }
+
+    #[inline(always)]
+    pub fn publish_nodes(&self) {
+        self.thread_nodes.store(self.nodes, Ordering::Relaxed);
+    }
+
+    /// Nodes searched by this thread and all of its helpers
+    pub fn total_nodes(&self) -> u64 {
+        self.nodes + self.helper_nodes.iter().map(|nodes| nodes.load(Ordering::Relaxed)).sum::<u64>()
+    }
+
     // position X
     pub fn clear_data(&mut self) {
         self.max_depth = 0;
         self.seldepth = 0;
-        self.killer_moves = [Move::create_null(); 256];
+        self.killer_moves = [[Move::create_null(); 2]; 256];
         self.move_stack.clear();
         self.nodes = 0;
         self.pv_table.clear(0);
-        self.stop_condition = StopCondition::default();
+        self.stop_condition = StopCondition::new(self.stop_condition.signals());
         self.stop_condition.soft_nodecount = self.engine_config.soft_nodes;
         self.should_quit = false;
         self.ply = 0;
@@ -73,7 +163,18 @@ impl SearchState {
     pub fn clear_persistent_data(&mut self) {
         self.tt.clear();
         self.history_moves = [[[0;64]; 64]; 2];
-        //self.capt_history_moves = [[[0; 64]; 12]; 12];
+        self.capt_history_moves = [[[0; 6]; 64]; 12];
+        self.continuation_history.iter_mut().for_each(ContinuationHistory::clear);
+        self.countermoves = [[Move::create_null(); 64]; 12];
+        self.correction_history.clear();
+
+        for helper in &mut self.helpers {
+            helper.history_moves = [[[0;64]; 64]; 2];
+            helper.capt_history_moves = [[[0; 6]; 64]; 12];
+            helper.continuation_history.iter_mut().for_each(ContinuationHistory::clear);
+            helper.countermoves = [[Move::create_null(); 64]; 12];
+            helper.correction_history.clear();
+        }
     }
 
     // ID
@@ -88,9 +189,9 @@ impl SearchState {
     }
 
     pub fn make_move(&mut self, mv: Move, board_position: &BoardPosition, static_eval: i32) {
-        self.move_stack.push(board_position.hash, static_eval); 
+        self.move_stack.push(board_position.hash, static_eval, mv, board_position.get_piece(mv)); 
         self.ply += 1;
-        self.network_state.apply_move(mv, board_position);
+        self.network_state.apply_move(mv, board_position, &self.engine_config.network);
     }
 
     pub fn take_back(&mut self) {
@@ -99,40 +200,112 @@ impl SearchState {
         self.network_state.undo_move();
     }
 
+    // Null moves leave the pieces alone, the stack only records that there's no previous move
+    pub fn make_null_move(&mut self, board_position: &BoardPosition, static_eval: i32) {
+        self.move_stack.push(board_position.hash, static_eval, Move::create_null(), Piece::NONE);
+        self.ply += 1;
+    }
+
+    pub fn take_back_null_move(&mut self) {
+        self.move_stack.pop();
+        self.ply -= 1;
+    }
+
     #[inline(always)]
     pub fn get_mvv_lva(victim: Piece, attacker: Piece) -> i32 {
         MVV_LVA[victim as usize % 6 + attacker as usize % 6 * 6]
     }
 
+    // The newest killer goes first, the older one is kept as the second killer
     pub fn update_killer_move(&mut self, mv: Move) {
+        if self.ply < 256 && self.killer_moves[self.ply][0] != mv {
+            self.killer_moves[self.ply][1] = self.killer_moves[self.ply][0];
+            self.killer_moves[self.ply][0] = mv;
+        }
+    }
+
+    pub fn get_killer_moves(&self) -> [Move; 2] {
         if self.ply < 256 {
-            self.killer_moves[self.ply] = mv;
+            self.killer_moves[self.ply]
+        } else {
+            [Move::create_null(); 2]
+        }
+    }
+
+    // Piece and target square of the move `plies_ago` plies back
+    #[inline(always)]
+    fn continuation_key(&self, plies_ago: usize) -> Option<(Piece, u8)> {
+        self.move_stack.previous_move(plies_ago).map(|(piece, mv)| (piece, mv.get_target_square()))
+    }
+
+    pub fn get_countermove(&self) -> Move {
+        match self.continuation_key(1) {
+            Some((piece, target)) => self.countermoves[piece as usize][target as usize],
+            None => Move::create_null(),
+        }
+    }
+
+    pub fn update_countermove(&mut self, mv: Move) {
+        if let Some((piece, target)) = self.continuation_key(1) {
+            self.countermoves[piece as usize][target as usize] = mv;
         }
     }
 
     pub fn update_history(&mut self, board_position: &BoardPosition, mv: Move, bonus: i32) {
-        let clamped_bonus = bonus.clamp(-MAX_HISTORY, MAX_HISTORY) as i32;
+        let clamped_bonus = bonus.clamp(-MAX_HISTORY, MAX_HISTORY);
         let piece = board_position.get_piece(mv) as usize;
         let source = mv.get_source_square();
         let target = mv.get_target_square();
         let side = board_position.side;
         if piece < 12 && target < 64 {
             let history_val = self.get_quiet_history(side, mv);           
-            self.history_moves[side][source as usize][target as usize] += (clamped_bonus - history_val as i32 * clamped_bonus.abs() / MAX_HISTORY) as i16 //second bonus should be abs
-            //if mv.is_capture() {
-            //    let history_val = self.capt_history_moves[self.board_position.mailbox[mv.get_target_square() as usize] as usize][piece][target];
-            //    self.capt_history_moves[self.board_position.mailbox[mv.get_target_square() as usize] as usize][piece][target] += clamped_bonus - history_val * clamped_bonus / MAX_HISTORY;
-            //} else {
-            //}
+            self.history_moves[side][source as usize][target as usize] += (clamped_bonus - history_val as i32 * clamped_bonus.abs() / MAX_HISTORY) as i16; //second bonus should be abs
+
+            for plies_ago in 1..=2 {
+                if let Some(previous) = self.continuation_key(plies_ago) {
+                    self.continuation_history[plies_ago - 1].update(previous, Piece::new(piece), target, clamped_bonus);
+                }
+            }
             //self.history_moves[piece][target] += bonus;
 
         }
     }
 
+    pub fn update_capture_history(&mut self, board_position: &BoardPosition, mv: Move, bonus: i32) {
+        let clamped_bonus = bonus.clamp(-MAX_HISTORY, MAX_HISTORY);
+        let history_val = self.get_capture_history(board_position, mv);
+        let piece = board_position.get_piece(mv) as usize;
+        let victim = board_position.get_victim(mv) as usize % 6;
+
+        self.capt_history_moves[piece][mv.get_target_square() as usize][victim] += (clamped_bonus - history_val as i32 * clamped_bonus.abs() / MAX_HISTORY) as i16;
+    }
+
+    pub fn get_capture_history(&self, board_position: &BoardPosition, mv: Move) -> i16 {
+        let piece = board_position.get_piece(mv) as usize;
+        let victim = board_position.get_victim(mv) as usize % 6;
+
+        self.capt_history_moves[piece][mv.get_target_square() as usize][victim]
+    }
+
     pub fn get_quiet_history(&self, side: Color, mv: Move) -> i16 {
         self.history_moves[side][mv.get_source_square() as usize][mv.get_target_square() as usize]
     }
 
+    /// Quiet history plus the continuation histories of the last 2 moves
+    pub fn get_quiet_score(&self, board_position: &BoardPosition, mv: Move) -> i32 {
+        let piece = board_position.get_piece(mv);
+        let target = mv.get_target_square();
+        let mut score = self.get_quiet_history(board_position.side, mv) as i32;
+
+        for plies_ago in 1..=2 {
+            if let Some(previous) = self.continuation_key(plies_ago) {
+                score += self.continuation_history[plies_ago - 1].get(previous, piece, target) as i32;
+            }
+        }
+
+        score
+    }
+
 
     // pub fn get_stats(&self) -> (u64, u64, f64) {
     //     let fill_pct = self.tt.fill_percentage();
@@ -148,11 +321,41 @@ impl SearchState {
     }
 
     #[inline(always)]
-    pub fn probe_tt(&self, hash: u64) -> Option<&TTEntry> {
+    pub fn probe_tt(&mut self, hash: u64) -> Option<TTEntry> {
         if self.engine_config.hash == 0 {
             return None
         }
-        self.tt.probe(hash)
+
+        self.tt_stats.probes += 1;
+        let probe = self.tt.probe(hash);
+        if probe.is_some() {
+            self.tt_stats.hits += 1;
+        }
+
+        probe
+    }
+
+    pub fn save_tt(&self, path: &str) -> io::Result<()> {
+        self.tt.save(path)
+    }
+
+    pub fn load_tt(&self, path: &str) -> io::Result<()> {
+        self.tt.load(path)
+    }
+
+    pub fn hashfull(&self) -> usize {
+        self.tt.hashfull()
+    }
+
+    /// TT counters of the last search, summed over all threads
+    pub fn print_tt_stats(&self) {
+        let mut tt_stats = self.tt_stats;
+        for helper in &self.helpers {
+            tt_stats.merge(&helper.tt_stats);
+        }
+
+        tt_stats.print();
+        println!("Hashfull: {}", self.hashfull());
     }
 
     // add static eval
@@ -170,7 +373,7 @@ impl SearchState {
             return;
         }
 
-        self.tt.store(
+        let outcome = self.tt.store(
             hash,
             depth,
             score_to_tt(score, self.ply),
@@ -178,9 +381,18 @@ impl SearchState {
             flag,
             best_move, // or .into()
         );
+        self.tt_stats.record_store(outcome);
     }
 }
 
+/// Flags shared between the UCI thread and a running search
+#[derive(Default)]
+pub struct SearchSignals {
+    // Raised on `stop`/`quit`
+    pub stop: AtomicBool,
+    // Set by `go ponder`, cleared by `ponderhit`
+    pub pondering: AtomicBool,
+}
 
 pub struct StopCondition {
     pub movetime_deadline: Option<u64>,
@@ -189,26 +401,57 @@ pub struct StopCondition {
     pub depth: Option<usize>,
     pub hard_nodecount: Option<u64>,
     pub soft_nodecount: Option<u64>,
+    // go infinite - search until told to stop
+    pub infinite: bool,
     pub started_search: Instant,
-    drop_everything_and_quit: bool 
+    drop_everything_and_quit: bool,
+    signals: Arc<SearchSignals>,
 }
 
 impl Default for StopCondition {
     fn default() -> Self {
+        StopCondition::new(Arc::new(SearchSignals::default()))
+    }
+}
+
+impl StopCondition {
+    pub fn new(signals: Arc<SearchSignals>) -> Self {
         StopCondition { movetime_deadline: None,
             our_time_ms: None,
             our_inc_ms: None,
             depth: None, 
             hard_nodecount: None, 
             soft_nodecount: None, 
+            infinite: false,
             started_search: Instant::now(),
-            drop_everything_and_quit: false 
+            drop_everything_and_quit: false,
+            signals
+        }
+    }
+
+    /// Handle to the shared flags, so another thread can stop the search or end pondering
+    pub fn signals(&self) -> Arc<SearchSignals> {
+        self.signals.clone()
+    }
+
+    /// Infinite and ponder searches ignore time and only end on `stop` (or `ponderhit`)
+    pub fn is_unbounded(&self) -> bool {
+        self.infinite || self.signals.pondering.load(Ordering::Relaxed)
+    }
+
+    /// UCI doesn't allow a bestmove before `stop`/`ponderhit` in infinite and ponder mode,
+    /// even if the search has run out of depth.
+    pub fn wait_until_bounded(&self) {
+        while self.is_unbounded() && !self.signals.stop.load(Ordering::Relaxed) {
+            thread::sleep(Duration::from_millis(1));
         }
     }
-}
 
-impl StopCondition {
     fn passed_deadline(&self) -> bool {
+        if self.is_unbounded() {
+            return false;
+        }
+
         let elapsed = self.started_search.elapsed().as_millis();
         
         if let Some(movetime_deadline) = self.movetime_deadline {
@@ -222,7 +465,7 @@ impl StopCondition {
                 return true;
             }
             
-            let our_inc = if let Some(our_inc) = self.our_inc_ms {our_inc} else { 0 };
+            let our_inc = self.our_inc_ms.unwrap_or_default();
             let allocation = our_time/15 + our_inc; 
 
             if elapsed > allocation {
@@ -234,6 +477,10 @@ impl StopCondition {
     }
     
     pub fn should_soft_quit(&self, depth: usize, nodes: u64) -> bool {
+        if self.is_unbounded() {
+            return false;
+        }
+
         if let Some(max_depth) = self.depth {
             if max_depth == depth {
                 return true;
@@ -253,7 +500,7 @@ impl StopCondition {
                 return true;
             }
             
-            let our_inc = if let Some(our_inc) = self.our_inc_ms {our_inc} else { 0 };
+            let our_inc = self.our_inc_ms.unwrap_or_default();
             let allocation = (our_time/15 + our_inc)/3; 
 
             if elapsed > allocation {
@@ -270,7 +517,12 @@ impl StopCondition {
             return true;
         }
 
-        if self.passed_deadline() && nodes % 1024 == 0 {
+        if self.signals.stop.load(Ordering::Relaxed) {
+            self.drop_everything_and_quit = true;
+            return true;
+        }
+
+        if self.passed_deadline() && nodes.is_multiple_of(1024) {
             self.drop_everything_and_quit = true;
             return true;
         }
@@ -287,10 +539,12 @@ impl StopCondition {
 
     pub fn reset(&mut self) {
         self.drop_everything_and_quit = false;
+        self.infinite = false;
         self.started_search = Instant::now();
     }
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Reporting {
     UCI,
@@ -311,6 +565,8 @@ mod tests {
     use crate::search::search; 
     use crate::search_objs::config::EngineConfig;
 use crate::search_objs::search_state::{SearchState};
+    use crate::primitives::board::BoardPosition;
+    use crate::primitives::shared::{Move, MoveCode, Piece};
 
     #[test]
     fn test_clearing_persistent_data_correctly() {
@@ -378,4 +634,31 @@ use crate::search_objs::search_state::{SearchState};
             .unwrap();
         handler.join().unwrap();
     }
+
+    #[test]
+    fn test_capture_history() {
+        let builder = thread::Builder::new().stack_size(80 * 1024 * 1024);
+        let handler = builder
+            .spawn(|| {
+                let mut search_state = SearchState::new(&EngineConfig::thin());
+                // exd5 and the en passant exf6 are both pawn takes pawn, Nxd5 isn't
+                let board_position = BoardPosition::new("rnbqkbnr/ppp1p1pp/8/3p1pP1/4P3/2N5/PPPP1P1P/R1BQKBNR w KQkq f6 0 1");
+                let pawn_takes = Move::create(36, 27, MoveCode::Capture);
+                let knight_takes = Move::create(42, 27, MoveCode::Capture);
+                let en_passant = Move::create(30, 21, MoveCode::EnPassant);
+
+                search_state.update_capture_history(&board_position, pawn_takes, 1000);
+                search_state.update_capture_history(&board_position, knight_takes, -1000);
+                search_state.update_capture_history(&board_position, en_passant, 500);
+                assert!(search_state.get_capture_history(&board_position, pawn_takes) > 0);
+                assert!(search_state.get_capture_history(&board_position, knight_takes) < 0);
+                assert!(search_state.get_capture_history(&board_position, en_passant) > 0);
+                assert_eq!(search_state.capt_history_moves[Piece::P as usize][21][Piece::P as usize], 500);
+
+                search_state.clear_persistent_data();
+                assert_eq!(search_state.get_capture_history(&board_position, pawn_takes), 0);
+            })
+            .unwrap();
+        handler.join().unwrap();
+    }
 }
@@ -0,0 +1,203 @@
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::thread::{self, JoinHandle};
+
+use crate::search_objs::search_state::{SearchSignals, SearchState};
+
+/// Owner of the search state while the UCI loop keeps reading stdin.
+/// A `go` runs on a worker thread which hands the state back once it's done.
+pub struct SearchThread {
+    search_state: Option<SearchState>,
+    handle: Option<JoinHandle<SearchState>>,
+    signals: Arc<SearchSignals>,
+}
+
+impl SearchThread {
+    pub fn new(search_state: SearchState) -> Self {
+        Self {
+            signals: search_state.stop_condition.signals(),
+            search_state: Some(search_state),
+            handle: None,
+        }
+    }
+
+    /// Run `job` on a fresh worker thread. Stops the previous search first.
+    pub fn spawn<F>(&mut self, job: F)
+    where
+        F: FnOnce(&mut SearchState) + Send + 'static,
+    {
+        self.stop();
+        let mut search_state = self.search_state.take().expect("search state is owned by the UCI thread");
+        self.signals.stop.store(false, Ordering::Relaxed);
+
+        let builder = thread::Builder::new().stack_size(80 * 1024 * 1024);
+        let handle = builder.spawn(move || {
+            job(&mut search_state);
+            search_state
+        }).unwrap();
+
+        self.handle = Some(handle);
+    }
+
+    /// Ask the running search to finish as soon as possible and wait for it
+    pub fn stop(&mut self) {
+        if self.is_searching() {
+            self.signals.stop.store(true, Ordering::Relaxed);
+        }
+        self.wait();
+    }
+
+    /// Set for `go ponder` before spawning, so an early `ponderhit` can't be lost.
+    /// Stop the previous search first, it reads the same flag.
+    pub fn set_pondering(&self, pondering: bool) {
+        self.signals.pondering.store(pondering, Ordering::Relaxed);
+    }
+
+    pub fn is_searching(&self) -> bool {
+        self.handle.as_ref().is_some_and(|handle| !handle.is_finished())
+    }
+
+    /// Block until the worker returns the search state
+    pub fn wait(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            self.search_state = Some(handle.join().unwrap());
+        }
+    }
+
+    /// Search state for commands that need it. A running search is stopped, even `go infinite` or `go ponder`,
+    /// so the UCI thread never blocks on it.
+    pub fn state(&mut self) -> &mut SearchState {
+        self.stop();
+        self.search_state.as_mut().unwrap()
+    }
+
+    // setoption rebuilds the whole search state
+    pub fn replace(&mut self, search_state: SearchState) {
+        self.stop();
+        self.signals = search_state.stop_condition.signals();
+        self.search_state = Some(search_state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use std::thread;
+    use crate::gui::{parse_go, parse_position_command};
+    use crate::search_objs::config::EngineConfig;
+    use crate::search_objs::search_state::{Reporting, SearchState};
+    use crate::search_objs::search_thread::SearchThread;
+
+    #[test]
+    fn test_stop_interrupts_search() {
+        let builder = thread::Builder::new().stack_size(80 * 1024 * 1024);
+        let handler = builder
+            .spawn(|| {
+                let mut search_state = SearchState::new(&EngineConfig::thin());
+                search_state.reporting = Reporting::Quiet;
+                let board_position = parse_position_command(&mut search_state, "position startpos");
+                let mut search_thread = SearchThread::new(search_state);
+
+                search_thread.spawn(move |search_state| parse_go(&board_position, search_state, "go depth 100"));
+                thread::sleep(Duration::from_millis(200));
+                assert!(search_thread.is_searching());
+
+                search_thread.stop();
+                assert!(!search_thread.is_searching());
+                assert!(search_thread.state().nodes > 0);
+            })
+            .unwrap();
+        handler.join().unwrap();
+    }
+
+    #[test]
+    fn test_infinite_runs_until_stop() {
+        let builder = thread::Builder::new().stack_size(80 * 1024 * 1024);
+        let handler = builder
+            .spawn(|| {
+                // Mate in one - the search runs out of depth quickly but must not return before stop
+                let mut search_state = SearchState::new(&EngineConfig::thin());
+                search_state.reporting = Reporting::Quiet;
+                let board_position = parse_position_command(&mut search_state, "position fen 7k/8/6K1/8/8/8/8/R7 w - - 0 1");
+                let mut search_thread = SearchThread::new(search_state);
+
+                search_thread.spawn(move |search_state| parse_go(&board_position, search_state, "go infinite"));
+                thread::sleep(Duration::from_millis(500));
+                assert!(search_thread.is_searching());
+
+                search_thread.stop();
+                assert!(!search_thread.is_searching());
+            })
+            .unwrap();
+        handler.join().unwrap();
+    }
+
+    #[test]
+    fn test_commands_stop_infinite_search() {
+        let builder = thread::Builder::new().stack_size(80 * 1024 * 1024);
+        let handler = builder
+            .spawn(|| {
+                let mut search_state = SearchState::new(&EngineConfig::thin());
+                search_state.reporting = Reporting::Quiet;
+                let board_position = parse_position_command(&mut search_state, "position startpos");
+                let mut search_thread = SearchThread::new(search_state);
+
+                // position/eval/setoption during go infinite must not wait for a stop that never comes
+                let board = board_position.clone();
+                search_thread.spawn(move |search_state| parse_go(&board, search_state, "go infinite"));
+                thread::sleep(Duration::from_millis(100));
+                assert!(search_thread.state().nodes > 0);
+                assert!(!search_thread.is_searching());
+
+                let board = board_position.clone();
+                search_thread.spawn(move |search_state| parse_go(&board, search_state, "go infinite"));
+                thread::sleep(Duration::from_millis(100));
+                search_thread.replace(SearchState::new(&EngineConfig::thin()));
+                assert!(!search_thread.is_searching());
+                search_thread.state().reporting = Reporting::Quiet;
+                let board_position = parse_position_command(search_thread.state(), "position startpos");
+
+                // A new go replaces a ponder search without touching its flag before it's gone
+                search_thread.set_pondering(true);
+                let board = board_position.clone();
+                search_thread.spawn(move |search_state| parse_go(&board, search_state, "go ponder"));
+                thread::sleep(Duration::from_millis(100));
+                search_thread.stop();
+                search_thread.set_pondering(false);
+                search_thread.spawn(move |search_state| parse_go(&board_position, search_state, "go depth 4"));
+                search_thread.wait();
+                assert!(!search_thread.is_searching());
+            })
+            .unwrap();
+        handler.join().unwrap();
+    }
+
+    #[test]
+    fn test_ponderhit_switches_to_time_management() {
+        let builder = thread::Builder::new().stack_size(80 * 1024 * 1024);
+        let handler = builder
+            .spawn(|| {
+                let mut search_state = SearchState::new(&EngineConfig::thin());
+                search_state.reporting = Reporting::Quiet;
+                let board_position = parse_position_command(&mut search_state, "position startpos");
+                let mut search_thread = SearchThread::new(search_state);
+
+                search_thread.set_pondering(true);
+                search_thread.spawn(move |search_state| parse_go(&board_position, search_state, "go ponder movetime 50"));
+                thread::sleep(Duration::from_millis(300));
+                // Still pondering, movetime doesn't apply yet
+                assert!(search_thread.is_searching());
+
+                search_thread.set_pondering(false);
+                for _ in 0..200 {
+                    if !search_thread.is_searching() {
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(10));
+                }
+                assert!(!search_thread.is_searching());
+            })
+            .unwrap();
+        handler.join().unwrap();
+    }
+}
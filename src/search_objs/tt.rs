@@ -4,26 +4,50 @@
 //! - Zobrist hash key generation for board positions
 //! - Transposition table for storing search results
 //! - Threefold repetition detection
+//!
+//! The table is shared between search threads, see `Bucket` for how entries are stored.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::sync::atomic::{AtomicU16, AtomicU64, AtomicU8, Ordering};
 
+use crate::primitives::hash::{ZOBRIST_SEED, get_zobrist_keys};
 use crate::primitives::shared::Move;
-use crate::primitives::consts::{MATE_THRESHOLD, NO_SCORE};
+use crate::primitives::consts::MATE_THRESHOLD;
+
+// Two bytes of key and eight of data per entry, six entries fill a 64 byte cache line
+pub const ENTRIES_PER_BUCKET: usize = 6;
+
+// Age lives in the upper 6 bits of the age/flag byte
+const AGE_CYCLE: u8 = 64;
 
-/// Size of the transposition table (number of entries)
-/// Using a power of 2 allows for fast modulo with bitwise AND
-// pub const TT_SIZE: usize = 1 << 20; // ~1 million entries, ~24MB
+// Saved hash files - bump the version whenever the entry layout changes
+const HASH_FILE_MAGIC: &[u8; 8] = b"DUALHASH";
+const HASH_FILE_VERSION: u32 = 1;
 
 /// Transposition table entry types
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
 pub enum TTFlag {
     Exact, // Score is exact
     Alpha, // Score is upper bound (fail low)
     Beta,  // Score is lower bound (fail high)
 }
 
-/// Transposition table entry
+impl TTFlag {
+    const fn from_u8(value: u8) -> TTFlag {
+        match value {
+            1 => TTFlag::Alpha,
+            2 => TTFlag::Beta,
+            _ => TTFlag::Exact,
+        }
+    }
+}
+
+/// Transposition table entry, unpacked
 #[derive(Clone, Copy)]
 pub struct TTEntry {
-    pub hash: u64,      // Full hash for verification
+    pub key: u16,       // Lower 16 bits of the hash, the bucket index covers the upper ones
     pub score: i32,     // Evaluated score
     pub eval: i32,
     pub best_move: Move, // Best move found (if any)
@@ -33,108 +57,332 @@ pub struct TTEntry {
 }
 
 impl TTEntry {
-    pub const fn empty() -> Self {
-        Self {
-            hash: 0,
-            score: 0,
-            eval: NO_SCORE,
-            flag: TTFlag::Exact,
-            best_move: Move::create_null(),
-            depth: 0,
-            age: 0,
+    /// Check if this entry is valid for the given hash
+    #[inline(always)]
+    pub fn matches(&self, hash: u64) -> bool {
+        self.key == key_of(hash)
+    }
+
+    // Data word layout: move | score | eval | depth | age (6 bits) flag + 1 (2 bits)
+    // The flag is stored off by one, so an occupied entry never has a zero data word
+    #[inline(always)]
+    const fn pack(&self) -> u64 {
+        let age_flag = (self.age % AGE_CYCLE) << 2 | (self.flag as u8 + 1);
+
+        self.best_move.to_u16() as u64
+            | (self.score as i16 as u16 as u64) << 16
+            | (self.eval as i16 as u16 as u64) << 32
+            | (self.depth as u64) << 48
+            | (age_flag as u64) << 56
+    }
+
+    #[inline(always)]
+    const fn unpack(key: u16, data: u64) -> Option<TTEntry> {
+        let age_flag = (data >> 56) as u8;
+
+        if age_flag & 3 == 0 {
+            return None;
         }
+
+        Some(TTEntry {
+            key,
+            best_move: Move::from_u16(data as u16),
+            score: (data >> 16) as u16 as i16 as i32,
+            eval: (data >> 32) as u16 as i16 as i32,
+            depth: (data >> 48) as u8,
+            age: age_flag >> 2,
+            flag: TTFlag::from_u8((age_flag & 3) - 1),
+        })
     }
+}
 
-    /// Check if this entry is valid for the given hash
+/// What `store` did with an entry
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TTStore {
+    Inserted, // Took an empty slot
+    Updated,  // Overwrote the same position
+    Replaced, // Evicted a different position
+    Rejected, // Kept what was in the table
+}
+
+/// Counters collected by a search thread for the `ttstats` command
+#[derive(Clone, Copy, Default)]
+pub struct TTStats {
+    pub probes: u64,
+    pub hits: u64,
+    pub cutoffs: u64,
+    pub inserted: u64,
+    pub updated: u64,
+    pub replaced: u64,
+    pub rejected: u64,
+}
+
+impl TTStats {
+    pub fn record_store(&mut self, outcome: TTStore) {
+        match outcome {
+            TTStore::Inserted => self.inserted += 1,
+            TTStore::Updated => self.updated += 1,
+            TTStore::Replaced => self.replaced += 1,
+            TTStore::Rejected => self.rejected += 1,
+        }
+    }
+
+    pub fn merge(&mut self, other: &TTStats) {
+        self.probes += other.probes;
+        self.hits += other.hits;
+        self.cutoffs += other.cutoffs;
+        self.inserted += other.inserted;
+        self.updated += other.updated;
+        self.replaced += other.replaced;
+        self.rejected += other.rejected;
+    }
+
+    pub fn print(&self) {
+        let percent = |part: u64, whole: u64| if whole == 0 { 0.0 } else { part as f64 * 100.0 / whole as f64 };
+        let stores = self.inserted + self.updated + self.replaced + self.rejected;
+
+        println!("TT probes: {}, hit rate: {:.1}%, cutoff rate: {:.1}% of hits", self.probes, percent(self.hits, self.probes), percent(self.cutoffs, self.hits));
+        println!("TT stores: {}, inserted: {}, updated: {}, replaced: {}, rejected: {}", stores, self.inserted, self.updated, self.replaced, self.rejected);
+    }
+}
+
+#[inline(always)]
+const fn key_of(hash: u64) -> u16 {
+    hash as u16
+}
+
+/// Entries sharing one cache line. The table is shared between search threads, every field
+/// is a relaxed atomic - a probe can still see the key of one write with the data of another,
+/// which the move validation in search guards against.
+#[repr(C, align(64))]
+#[derive(Default)]
+struct Bucket {
+    data: [AtomicU64; ENTRIES_PER_BUCKET],
+    keys: [AtomicU16; ENTRIES_PER_BUCKET],
+}
+
+impl Bucket {
     #[inline(always)]
-    pub fn matches(&self, hash: u64) -> bool {
-        self.hash == hash
+    fn load(&self, slot: usize) -> Option<TTEntry> {
+        TTEntry::unpack(self.keys[slot].load(Ordering::Relaxed), self.data[slot].load(Ordering::Relaxed))
+    }
+
+    #[inline(always)]
+    fn save(&self, slot: usize, entry: &TTEntry) {
+        self.keys[slot].store(entry.key, Ordering::Relaxed);
+        self.data[slot].store(entry.pack(), Ordering::Relaxed);
+    }
+
+    fn clear(&self) {
+        for slot in 0..ENTRIES_PER_BUCKET {
+            self.keys[slot].store(0, Ordering::Relaxed);
+            self.data[slot].store(0, Ordering::Relaxed);
+        }
     }
 }
 
-/// Transposition table using fixed-size array
-/// Using a simple direct indexing scheme for speed
+/// Transposition table of cache-line sized buckets
 pub struct TranspositionTable {
-    entries: Vec<TTEntry>,
-    age: u8,
-    tt_size: usize
+    buckets: Vec<Bucket>,
+    age: AtomicU8,
 }
 
 impl TranspositionTable {
     pub fn new(hash_size: usize) -> Self {
-        let nr_entries = 1024*1024*hash_size/size_of::<TTEntry>();
-        //let nr_entries_pow2 = if hash_size == 0 { 0 } else { 1 << (64 - nr_entries.leading_zeros() - 1) }; // Biggest smaller pow2
+        let nr_buckets = 1024*1024*hash_size/size_of::<Bucket>();
 
         Self {
-            entries: vec![TTEntry::empty(); nr_entries],
-            age: 0,
-            tt_size: nr_entries
+            buckets: (0..nr_buckets).map(|_| Bucket::default()).collect(),
+            age: AtomicU8::new(0),
         }
     }
 
     /// Clear the transposition table
-    pub fn clear(&mut self) {
-        for entry in &mut self.entries {
-            *entry = TTEntry::empty();
+    pub fn clear(&self) {
+        for bucket in &self.buckets {
+            bucket.clear();
         }
     }
 
     /// Increment the search age
-    pub fn increment_age(&mut self) {
-        self.age = self.age.wrapping_add(1);
+    pub fn increment_age(&self) {
+        self.age.store((self.age() + 1) % AGE_CYCLE, Ordering::Relaxed);
+    }
+
+    #[inline(always)]
+    fn age(&self) -> u8 {
+        self.age.load(Ordering::Relaxed)
+    }
+
+    /// Write every entry to `path`, behind a header describing the table and the hash keys
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        writer.write_all(HASH_FILE_MAGIC)?;
+        writer.write_all(&HASH_FILE_VERSION.to_le_bytes())?;
+        writer.write_all(&ZOBRIST_SEED.to_le_bytes())?;
+        writer.write_all(&get_zobrist_keys().fingerprint().to_le_bytes())?;
+        writer.write_all(&(self.buckets.len() as u64).to_le_bytes())?;
+        writer.write_all(&(ENTRIES_PER_BUCKET as u32).to_le_bytes())?;
+        writer.write_all(&[self.age()])?;
+
+        for bucket in &self.buckets {
+            for slot in 0..ENTRIES_PER_BUCKET {
+                writer.write_all(&bucket.keys[slot].load(Ordering::Relaxed).to_le_bytes())?;
+                writer.write_all(&bucket.data[slot].load(Ordering::Relaxed).to_le_bytes())?;
+            }
+        }
+
+        writer.flush()
+    }
+
+    /// Replace the table with a file written by `save`. Files from a different table size,
+    /// entry layout or key set are refused and leave the table untouched.
+    pub fn load(&self, path: &str) -> io::Result<()> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != HASH_FILE_MAGIC {
+            return Err(invalid_hash_file("not a hash file".to_owned()));
+        }
+
+        let version = u32::from_le_bytes(read_bytes(&mut reader)?);
+        if version != HASH_FILE_VERSION {
+            return Err(invalid_hash_file(format!("file version {}, expected {}", version, HASH_FILE_VERSION)));
+        }
+
+        let seed = u64::from_le_bytes(read_bytes(&mut reader)?);
+        let fingerprint = u64::from_le_bytes(read_bytes(&mut reader)?);
+        if seed != ZOBRIST_SEED || fingerprint != get_zobrist_keys().fingerprint() {
+            return Err(invalid_hash_file("saved with different Zobrist keys".to_owned()));
+        }
+
+        let nr_buckets = u64::from_le_bytes(read_bytes(&mut reader)?);
+        let entries_per_bucket = u32::from_le_bytes(read_bytes(&mut reader)?);
+        if nr_buckets != self.buckets.len() as u64 || entries_per_bucket != ENTRIES_PER_BUCKET as u32 {
+            return Err(invalid_hash_file(format!(
+                "saved with Hash {} MB, current Hash is {} MB",
+                nr_buckets * size_of::<Bucket>() as u64 / (1024 * 1024),
+                self.buckets.len() * size_of::<Bucket>() / (1024 * 1024),
+            )));
+        }
+
+        let [age] = read_bytes(&mut reader)?;
+
+        // Read everything before touching the table, a truncated file must not leave it half loaded
+        let mut entries = vec![0u8; self.buckets.len() * ENTRIES_PER_BUCKET * 10];
+        reader.read_exact(&mut entries)?;
+
+        for (bucket, chunk) in self.buckets.iter().zip(entries.chunks_exact(ENTRIES_PER_BUCKET * 10)) {
+            for (slot, entry) in chunk.chunks_exact(10).enumerate() {
+                bucket.keys[slot].store(u16::from_le_bytes([entry[0], entry[1]]), Ordering::Relaxed);
+                bucket.data[slot].store(u64::from_le_bytes(entry[2..].try_into().unwrap()), Ordering::Relaxed);
+            }
+        }
+        self.age.store(age % AGE_CYCLE, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    /// Permille of the first 1000 slots holding an entry from the current search
+    pub fn hashfull(&self) -> usize {
+        let age = self.age();
+        let mut sampled = 0;
+        let mut current: usize = 0;
+
+        for entry in self.buckets.iter().flat_map(|bucket| (0..ENTRIES_PER_BUCKET).map(|slot| bucket.load(slot))).take(1000) {
+            sampled += 1;
+            if entry.is_some_and(|entry| entry.age == age) {
+                current += 1;
+            }
+        }
+
+        (current * 1000).checked_div(sampled).unwrap_or(0)
     }
 
-    /// Get index into the table from hash
+    /// Get the bucket for a hash - multiply-shift maps the hash onto any table size without a division
     #[inline(always)]
-    fn index(&self, hash: u64) -> usize {
-        (hash as usize) % self.tt_size
+    fn bucket(&self, hash: u64) -> &Bucket {
+        let idx = ((hash as u128 * self.buckets.len() as u128) >> 64) as usize;
+        &self.buckets[idx]
     }
 
     /// Probe the transposition table
     #[inline]
-    pub fn probe(&self, hash: u64) -> Option<&TTEntry> {
-        let idx = self.index(hash);
-        let entry = &self.entries[idx];
+    pub fn probe(&self, hash: u64) -> Option<TTEntry> {
+        let bucket = self.bucket(hash);
 
-        if entry.matches(hash) {
-            Some(entry)
-        } else {
-            None
-        }
+        (0..ENTRIES_PER_BUCKET)
+            .filter_map(|slot| bucket.load(slot))
+            .find(|entry| entry.matches(hash))
     }
 
     /// Store an entry in the transposition table
     #[inline]
-    pub fn store(&mut self, hash: u64, depth: u8, score: i32, eval: i32, flag: TTFlag, best_move: Move) {
-        let idx = self.index(hash);
-        let entry = &mut self.entries[idx];
-
-        if entry.hash == 0 || !entry.matches(hash) && depth as i32 - entry.depth as i32 + (self.age.wrapping_sub(entry.age) as i32 * 6) > 0 {
-            *entry = TTEntry {
-                hash,
-                depth,
-                score,
-                eval,
-                flag,
-                best_move,
-                age: self.age,
-            };
-        } else if entry.matches(hash) && matches_replacement_strength(depth, flag) >= matches_replacement_strength(entry.depth, entry.flag) {
-            let mv = if best_move.is_null() { entry.best_move } else {best_move};
-
-            *entry = TTEntry {
-                hash,
-                depth,
-                score,
-                eval,
-                flag,
-                best_move: mv,
-                age: self.age,
+    pub fn store(&self, hash: u64, depth: u8, score: i32, eval: i32, flag: TTFlag, best_move: Move) -> TTStore {
+        let bucket = self.bucket(hash);
+        let age = self.age();
+
+        let mut new_entry = TTEntry {
+            key: key_of(hash),
+            depth,
+            score,
+            eval,
+            flag,
+            best_move,
+            age,
+        };
+
+        // Replacement priority of a different position, lowest goes first.
+        // Old entries lose 6 depth per search they've been sitting in the table.
+        let mut replace_slot = 0;
+        let mut replace_priority = i32::MAX;
+
+        for slot in 0..ENTRIES_PER_BUCKET {
+            let Some(entry) = bucket.load(slot) else {
+                // Slots fill up in order, nothing further in the bucket
+                bucket.save(slot, &new_entry);
+                return TTStore::Inserted;
             };
+
+            if entry.matches(hash) {
+                if matches_replacement_strength(depth, flag) >= matches_replacement_strength(entry.depth, entry.flag) {
+                    if best_move.is_null() {
+                        new_entry.best_move = entry.best_move;
+                    }
+                    bucket.save(slot, &new_entry);
+                    return TTStore::Updated;
+                }
+                return TTStore::Rejected;
+            }
+
+            let priority = entry.depth as i32 - (age.wrapping_sub(entry.age) % AGE_CYCLE) as i32 * 6;
+            if priority < replace_priority {
+                replace_slot = slot;
+                replace_priority = priority;
+            }
+        }
+
+        if depth as i32 > replace_priority {
+            bucket.save(replace_slot, &new_entry);
+            TTStore::Replaced
+        } else {
+            TTStore::Rejected
         }
     }
 }
 
+fn read_bytes<const N: usize>(reader: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn invalid_hash_file(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
 #[inline]
 pub fn matches_replacement_strength(depth: u8, flag: TTFlag) -> u8 {
     depth + if flag == TTFlag::Exact {
@@ -164,4 +412,120 @@ pub fn score_to_tt(score: i32, ply: usize) -> i32 {
     } else {
         score
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::primitives::consts::MATE_SCORE;
+    use crate::primitives::shared::{Move, MoveCode};
+    use crate::search_objs::tt::{ENTRIES_PER_BUCKET, TTEntry, TTFlag, TTStore, TranspositionTable};
+
+    #[test]
+    fn test_entry_packing_round_trip() {
+        let entry = TTEntry {
+            key: 0xBEEF,
+            score: -MATE_SCORE + 7,
+            eval: -1234,
+            best_move: Move::create(12, 28, MoveCode::DoublePush),
+            depth: 200,
+            age: 63,
+            flag: TTFlag::Beta,
+        };
+
+        let unpacked = TTEntry::unpack(entry.key, entry.pack()).unwrap();
+        assert_eq!(unpacked.key, entry.key);
+        assert_eq!(unpacked.score, entry.score);
+        assert_eq!(unpacked.eval, entry.eval);
+        assert!(unpacked.best_move == entry.best_move);
+        assert_eq!(unpacked.depth, entry.depth);
+        assert_eq!(unpacked.age, entry.age);
+        assert_eq!(unpacked.flag, entry.flag);
+
+        // A cleared slot is never mistaken for an entry
+        assert!(TTEntry::unpack(0, 0).is_none());
+    }
+
+    #[test]
+    fn test_bucket_replacement() {
+        let tt = TranspositionTable::new(1);
+        // Same upper bits - same bucket, different keys
+        let hashes: Vec<u64> = (1..=ENTRIES_PER_BUCKET as u64 + 1).collect();
+
+        for (i, &hash) in hashes.iter().take(ENTRIES_PER_BUCKET).enumerate() {
+            tt.store(hash, 10 + i as u8, 0, 0, TTFlag::Exact, Move::create_null());
+        }
+        for &hash in hashes.iter().take(ENTRIES_PER_BUCKET) {
+            assert!(tt.probe(hash).is_some());
+        }
+
+        // Full bucket - a shallow entry doesn't push anything out
+        let extra = hashes[ENTRIES_PER_BUCKET];
+        assert_eq!(tt.store(extra, 5, 0, 0, TTFlag::Exact, Move::create_null()), TTStore::Rejected);
+        assert!(tt.probe(extra).is_none());
+
+        // A deep one replaces the shallowest
+        assert_eq!(tt.store(extra, 30, 0, 0, TTFlag::Exact, Move::create_null()), TTStore::Replaced);
+        assert_eq!(tt.probe(extra).unwrap().depth, 30);
+        assert!(tt.probe(hashes[0]).is_none());
+
+        // Entries from older searches are replaced first
+        tt.increment_age();
+        tt.increment_age();
+        tt.store(hashes[0], 12, 0, 0, TTFlag::Exact, Move::create_null());
+        assert!(tt.probe(hashes[0]).is_some());
+        assert!(tt.probe(hashes[1]).is_none());
+
+        // Same position - kept unless the new entry is at least as strong
+        assert_eq!(tt.store(extra, 20, 0, 0, TTFlag::Alpha, Move::create_null()), TTStore::Rejected);
+        assert_eq!(tt.probe(extra).unwrap().depth, 30);
+        assert_eq!(tt.store(extra, 31, 0, 0, TTFlag::Alpha, Move::create_null()), TTStore::Updated);
+        assert_eq!(tt.probe(extra).unwrap().depth, 31);
+    }
+
+    #[test]
+    fn test_hashfull() {
+        let tt = TranspositionTable::new(1);
+        assert_eq!(tt.hashfull(), 0);
+
+        // 1MB is 2^14 buckets, so the top 14 bits of the hash pick the bucket.
+        // One entry in each of the first 50 buckets - 50 of the first 1000 slots
+        for bucket in 0..50u64 {
+            tt.store(bucket << 50 | 1, 1, 0, 0, TTFlag::Exact, Move::create_null());
+        }
+        assert_eq!(tt.hashfull(), 50);
+
+        // Entries from an earlier search don't count
+        tt.increment_age();
+        assert_eq!(tt.hashfull(), 0);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join("dual_test_hash.bin");
+        let path = path.to_str().unwrap();
+
+        let tt = TranspositionTable::new(1);
+        let best_move = Move::create(12, 28, MoveCode::DoublePush);
+        tt.increment_age();
+        tt.store(0x1234_5678_9ABC_DEF0, 9, -150, 40, TTFlag::Alpha, best_move);
+        tt.save(path).unwrap();
+
+        let loaded = TranspositionTable::new(1);
+        loaded.load(path).unwrap();
+        let entry = loaded.probe(0x1234_5678_9ABC_DEF0).unwrap();
+        assert_eq!(entry.depth, 9);
+        assert_eq!(entry.score, -150);
+        assert_eq!(entry.eval, 40);
+        assert_eq!(entry.flag, TTFlag::Alpha);
+        assert!(entry.best_move == best_move);
+        assert_eq!(loaded.hashfull(), tt.hashfull());
+
+        // Different Hash size - refused, table left as it was
+        let other_size = TranspositionTable::new(2);
+        let err = other_size.load(path).unwrap_err();
+        std::fs::remove_file(path).ok();
+
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(other_size.probe(0x1234_5678_9ABC_DEF0).is_none());
+    }
+}